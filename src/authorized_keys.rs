@@ -0,0 +1,276 @@
+/// Options that lift restrictions of `restrict` again or allow to escape a forced command
+const UNRESTRICTING_OPTIONS: &[&str] = &[
+    "agent-forwarding",
+    "command",
+    "environment",
+    "permitlisten",
+    "permitopen",
+    "port-forwarding",
+    "pty",
+    "restrict",
+    "tunnel",
+    "user-rc",
+    "x11-forwarding",
+];
+
+/// A line of an authorized_keys file (see AUTHORIZED_KEYS FILE FORMAT in man sshd)
+#[derive(Debug, PartialEq)]
+pub enum Line {
+    /// empty lines and comments are kept as they are
+    Other(String),
+    Key {
+        /// comma separated options like `from="10.0.0.0/8"` or `cert-authority`
+        options: Vec<String>,
+        /// key type, base64 encoded key and comment
+        key: String,
+    },
+}
+
+fn is_key_type(token: &str) -> bool {
+    token.starts_with("ssh-") || token.starts_with("ecdsa-") || token.starts_with("sk-")
+}
+
+/// Split at the first unquoted occurrence of `separator`. Inside double quotes, `\"` is an escaped
+/// quote.
+fn split_unquoted(s: &str, separator: impl Fn(char) -> bool) -> (&str, &str) {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if !quoted && separator(c) => return (&s[..i], &s[i + c.len_utf8()..]),
+            _ => {},
+        }
+    }
+    (s, "")
+}
+
+fn split_options(mut options: &str) -> Vec<String> {
+    let mut split = vec![];
+    while !options.is_empty() {
+        let (option, rest) = split_unquoted(options, |c| c == ',');
+        split.push(option.to_string());
+        options = rest;
+    }
+    split
+}
+
+fn option_name(option: &str) -> String {
+    option.split('=').next().unwrap_or(option).to_lowercase()
+}
+
+/// The options that `restrict` puts in front of every key
+fn restricting_options(command: &str, pty: bool) -> Vec<String> {
+    let command = command.replace('\\', "\\\\").replace('"', "\\\"");
+    let mut options = vec![String::from("restrict")];
+    if pty {
+        options.push(String::from("pty"));
+    }
+    options.push(format!("command=\"{}\"", command));
+    options
+}
+
+impl Line {
+    pub fn parse(line: &str) -> Line {
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            return Line::Other(line.to_string());
+        }
+        let first = trimmed.split_whitespace().next().unwrap_or("");
+        if is_key_type(first) {
+            return Line::Key { options: vec![], key: trimmed.to_string() };
+        }
+        let (options, key) = split_unquoted(trimmed, char::is_whitespace);
+        Line::Key { options: split_options(options), key: key.trim_start().to_string() }
+    }
+
+    /// Parse a line of an AuthorizedPrincipalsFile (see AUTHORIZED_KEYS FILE FORMAT in man sshd):
+    /// a principal name, optionally preceded by options.
+    pub fn parse_principal(line: &str) -> Line {
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            return Line::Other(line.to_string());
+        }
+        match split_unquoted(trimmed, char::is_whitespace) {
+            (principal, "") => Line::Key { options: vec![], key: principal.to_string() },
+            (options, principal) => Line::Key { options: split_options(options), key: principal.trim_start().to_string() },
+        }
+    }
+
+    /// Force `command` for this key and disable everything else it could be used for (except a
+    /// pty with `pty`). Options like `from=` or `cert-authority` are kept, options that would
+    /// weaken the restriction are dropped.
+    pub fn restrict(self, command: &str, pty: bool) -> Line {
+        match self {
+            Line::Other(line) => Line::Other(line),
+            Line::Key { options, key } => {
+                let mut restricted = restricting_options(command, pty);
+                restricted.extend(
+                    options.into_iter().filter(|o| !UNRESTRICTING_OPTIONS.contains(&option_name(o).as_str())),
+                );
+                Line::Key { options: restricted, key }
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Line {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Line::Other(line) => write!(f, "{}", line),
+            Line::Key { options, key } if options.is_empty() => write!(f, "{}", key),
+            Line::Key { options, key } => write!(f, "{} {}", options.join(","), key),
+        }
+    }
+}
+
+/// keep the newline at the end of a file, so that lines appended to the overlay stay separate
+fn trailing_newline(text: &str) -> &'static str {
+    match text.ends_with('\n') {
+        true => "\n",
+        false => "",
+    }
+}
+
+/// Rewrite an authorized_keys file so that every key only runs `command`
+pub fn restrict(authorized_keys: &str, command: &str, pty: bool) -> String {
+    authorized_keys
+        .lines()
+        .map(|line| Line::parse(line).restrict(command, pty).to_string())
+        .collect::<Vec<String>>()
+        .join("\n")
+        + trailing_newline(authorized_keys)
+}
+
+/// Rewrite an AuthorizedPrincipalsFile so that every principal only runs `command`
+pub fn restrict_principals(principals: &str, command: &str, pty: bool) -> String {
+    principals
+        .lines()
+        .map(|line| Line::parse_principal(line).restrict(command, pty).to_string())
+        .collect::<Vec<String>>()
+        .join("\n")
+        + trailing_newline(principals)
+}
+
+/// Remove the options that `restrict` put in front of a line, keeping the original options after
+/// them. Lines that we did not restrict are returned as they are.
+pub fn unrestrict(line: &str, command: &str, pty: bool) -> String {
+    let prefix = restricting_options(command, pty).join(",");
+    let rest = match line.trim_start().strip_prefix(&prefix) {
+        Some(rest) => rest,
+        None => return line.to_string(),
+    };
+    match rest.chars().next() {
+        Some(',') => rest[1..].to_string(),
+        Some(c) if c.is_whitespace() => rest.trim_start().to_string(),
+        _ => line.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMMAND: &str = "sudo hosthog status";
+    const KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIExample alice@laptop";
+
+    fn key(options: &[&str], key: &str) -> Line {
+        Line::Key { options: options.iter().map(|o| o.to_string()).collect(), key: key.to_string() }
+    }
+
+    #[test]
+    fn splits_unquoted() {
+        assert_eq!(split_unquoted("a,b,c", |c| c == ','), ("a", "b,c"));
+        assert_eq!(split_unquoted("from=\"a,b\",c", |c| c == ','), ("from=\"a,b\"", "c"));
+        assert_eq!(split_unquoted("command=\"echo \\\"x y\\\"\" key", char::is_whitespace), ("command=\"echo \\\"x y\\\"\"", "key"));
+        assert_eq!(split_unquoted("abc", |c| c == ','), ("abc", ""));
+    }
+
+    #[test]
+    fn parses_keys_without_options() {
+        assert_eq!(Line::parse(KEY), key(&[], KEY));
+        let ecdsa = "ecdsa-sha2-nistp256 AAAAE2VjZHNh bob";
+        assert_eq!(Line::parse(ecdsa), key(&[], ecdsa));
+        let sk = "sk-ssh-ed25519@openssh.com AAAAGnNr carol";
+        assert_eq!(Line::parse(sk), key(&[], sk));
+        let ecdsa_sk = "sk-ecdsa-sha2-nistp256@openssh.com AAAAInNr dave";
+        assert_eq!(Line::parse(ecdsa_sk), key(&[], ecdsa_sk));
+    }
+
+    #[test]
+    fn parses_quoted_options() {
+        let line = format!("from=\"10.0.0.1,10.0.0.2\",command=\"echo \\\"a, b\\\"\",no-pty {}", KEY);
+        assert_eq!(Line::parse(&line), key(&["from=\"10.0.0.1,10.0.0.2\"", "command=\"echo \\\"a, b\\\"\"", "no-pty"], KEY));
+        assert_eq!(Line::parse(&line).to_string(), line);
+    }
+
+    #[test]
+    fn keeps_comments_and_blank_lines() {
+        for line in ["", "   ", "# ssh-ed25519 AAAA disabled", "  # indented comment"] {
+            assert_eq!(Line::parse(line), Line::Other(line.to_string()));
+            assert_eq!(Line::parse(line).to_string(), line);
+            assert_eq!(Line::parse_principal(line).to_string(), line);
+        }
+    }
+
+    #[test]
+    fn parses_principals() {
+        assert_eq!(Line::parse_principal("alice"), key(&[], "alice"));
+        assert_eq!(Line::parse_principal("from=\"10.0.0.0/8\" alice"), key(&["from=\"10.0.0.0/8\""], "alice"));
+    }
+
+    #[test]
+    fn restricts_keys() {
+        let line = format!("from=\"10.0.0.0/8\",command=\"/bin/backup\",pty,no-agent-forwarding {}", KEY);
+        assert_eq!(
+            restrict(&line, COMMAND, false),
+            format!("restrict,command=\"sudo hosthog status\",from=\"10.0.0.0/8\",no-agent-forwarding {}", KEY)
+        );
+        assert_eq!(restrict(KEY, COMMAND, true), format!("restrict,pty,command=\"sudo hosthog status\" {}", KEY));
+        assert_eq!(restrict(KEY, "echo \"hi\"", false), format!("restrict,command=\"echo \\\"hi\\\"\" {}", KEY));
+    }
+
+    #[test]
+    fn restricting_is_idempotent() {
+        let restricted = restrict(&format!("{}\n# comment\n", KEY), COMMAND, true);
+        assert_eq!(restrict(&restricted, COMMAND, true), restricted);
+    }
+
+    #[test]
+    fn keeps_the_trailing_newline() {
+        assert_eq!(restrict(&format!("{}\n\n# c\n", KEY), COMMAND, false), format!("restrict,command=\"sudo hosthog status\" {}\n\n# c\n", KEY));
+        assert_eq!(restrict(KEY, COMMAND, false).lines().count(), 1);
+        assert!(!restrict(KEY, COMMAND, false).ends_with('\n'));
+        assert_eq!(restrict_principals("alice\n", COMMAND, false), "restrict,command=\"sudo hosthog status\" alice\n");
+        assert_eq!(restrict("", COMMAND, false), "");
+    }
+
+    #[test]
+    fn unrestrict_undoes_restrict() {
+        let lines = [
+            KEY.to_string(),
+            format!("from=\"10.0.0.1,10.0.0.2\",no-pty {}", KEY),
+            format!("cert-authority,principals=\"a,b\" {}", KEY),
+            String::from("# comment"),
+            String::new(),
+        ];
+        for pty in [false, true] {
+            for line in &lines {
+                let restricted = restrict(line, COMMAND, pty);
+                assert_eq!(unrestrict(&restricted, COMMAND, pty), *line);
+            }
+        }
+    }
+
+    #[test]
+    fn unrestrict_leaves_other_lines_alone() {
+        let line = format!("restrict,command=\"other\" {}", KEY);
+        assert_eq!(unrestrict(&line, COMMAND, false), line);
+        assert_eq!(unrestrict(KEY, COMMAND, false), KEY);
+        // our prefix, but followed by something else than an option or the key
+        let line = "restrict,command=\"sudo hosthog status\"x";
+        assert_eq!(unrestrict(line, COMMAND, false), line);
+    }
+}
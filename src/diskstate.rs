@@ -2,9 +2,14 @@ use serde::{Serialize, Deserialize};
 use chrono::prelude::*;
 use crate::users;
 use crate::util;
+use crate::sshd_config::SshdConfig;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::os::fd::AsRawFd;
 use once_cell::sync::Lazy;
 
 static STATE_FILE: Lazy<String> = Lazy::new(|| format!("{}/hosthog.json", util::STATE_PATH));
+static LOCK_FILE: Lazy<String> = Lazy::new(|| format!("{}/hosthog.lock", util::STATE_PATH));
 const SUPPORTED_STATE_VERSIONS: [u32; 1] = [ 2 ];
 const DEFAULT_STATE_VERSION: u32 = 2;
 
@@ -17,14 +22,108 @@ pub struct Claim {
     pub comment: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct Settings {
     /// This should be the same as AuthorizedKeysFile in /etc/ssh/sshd_config (see man
-    /// sshd_config)
+    /// sshd_config). Patterns that sshd uses for a user are hogged as well, even if they are
+    /// missing here.
     pub authorized_keys_file: Vec<String>,
+    /// PAM services (wildcards allowed) in which `hosthog pam-check` denies users that are locked
+    /// out
+    #[serde(default = "default_pam_services")]
+    pub pam_services: Vec<String>,
+    /// how users are locked out
+    #[serde(default)]
+    pub backend: Backend,
+    /// what locked out users get when they log in with a key
+    #[serde(default)]
+    pub gate: Gate,
+    /// uid ranges (`1000-`, `1000-59999` or `1001`) of the users that a hog locks out. System
+    /// and service accounts outside of them are left alone.
+    #[serde(default = "default_lockout_uids")]
+    pub lockout_uids: Vec<String>,
+    /// login shells (wildcards allowed) of users that a hog leaves alone
+    #[serde(default = "default_lockout_skip_shells")]
+    pub lockout_skip_shells: Vec<String>,
+    /// users (wildcards allowed) that a hog never locks out, e.g. monitoring accounts
+    #[serde(default)]
+    pub lockout_exclude: Vec<String>,
+    /// break-glass accounts that are always allowed, whatever is passed to `hog`
+    #[serde(default = "default_exempt_users")]
+    pub exempt_users: Vec<String>,
+    /// directories that contain home directories. Users are also found by the names of their home
+    /// directories, in case NSS does not enumerate them (SSSD with `enumerate = false`).
+    #[serde(default = "default_home_roots")]
+    pub home_roots: Vec<String>,
+}
+
+impl Settings {
+    /// whether a hog locks out this user (unless it is allowed)
+    pub fn locks_out(&self, user: &hog::User) -> bool {
+        if self.exempt_users.contains(&user.name) {
+            return false;
+        }
+        let in_range = self.lockout_uids.iter().any(|range| match parse_uid_range(range) {
+            Ok((first, last)) => first <= user.uid && user.uid <= last,
+            Err(_) => false,
+        });
+        in_range
+            && !self.lockout_skip_shells.iter().any(|shell| util::wildcard_match(shell, &user.shell))
+            && !self.lockout_exclude.iter().any(|name| util::wildcard_match(name, &user.name))
+    }
+}
+
+/// parse `first-last`, `first-` or a single uid
+pub fn parse_uid_range(range: &str) -> Result<(u32, u32), String> {
+    let parse = |uid: &str| uid.trim().parse::<u32>().map_err(|e| format!("invalid uid range {}: {}", range, e));
+    match range.split_once('-') {
+        Some((first, "")) => Ok((parse(first)?, u32::MAX)),
+        Some((first, last)) => Ok((parse(first)?, parse(last)?)),
+        None => Ok((parse(range)?, parse(range)?)),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// bind-mount restricted copies over authorized_keys files
+    #[default]
+    Overmount,
+    /// write /run/nologin (needs pam_nologin)
+    Nologin,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Gate {
+    /// only the hog message (`hosthog status`)
+    #[default]
+    Hard,
+    /// the hog message and a normal shell after typing a confirmation. The hogger is notified.
+    Soft,
+}
+
+fn default_exempt_users() -> Vec<String> {
+    vec![String::from("root")]
+}
+
+fn default_home_roots() -> Vec<String> {
+    vec![String::from("/home")]
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+fn default_lockout_uids() -> Vec<String> {
+    vec![String::from("1000-")]
+}
+
+fn default_lockout_skip_shells() -> Vec<String> {
+    vec![String::from("*/nologin"), String::from("*/false")]
+}
+
+fn default_pam_services() -> Vec<String> {
+    ["sshd", "login", "su", "su-l", "xrdp-sesman"].iter().map(|s| s.to_string()).collect()
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct DiskState {
     // Claim under which the system is currently hogged
     pub hogger: Option<Claim>,
@@ -38,6 +137,202 @@ pub struct DiskState {
     pub settings: Settings,
     pub disabled_systemd_units: Vec<String>,
     pub state_version: u32,
+    /// hog or release that is currently in progress. If this is set while no hosthog is running,
+    /// the previous run was interrupted.
+    #[serde(default)]
+    pub transaction: Option<Transaction>,
+    /// who changed what (oldest first)
+    #[serde(default)]
+    pub history: Vec<HistoryEntry>,
+    /// users that are not locked out by the current hog
+    #[serde(default)]
+    pub allowed_users: Vec<String>,
+    /// ways to log in via ssh and whether the current hog blocks them
+    #[serde(default)]
+    pub auth_paths: Vec<AuthPath>,
+    /// locked out users whose keys could not be restricted, and whether pam-check blocks them
+    #[serde(default)]
+    pub user_coverage: Vec<AuthPath>,
+    /// keys commands in overmounts that we replaced by a wrapper. Their overlays are ours, not
+    /// edits of the admin.
+    #[serde(default)]
+    pub wrapped_commands: Vec<String>,
+    /// overmounts that are AuthorizedPrincipalsFiles, not key files
+    #[serde(default)]
+    pub principals_files: Vec<String>,
+    /// whether we created /run/nologin
+    #[serde(default)]
+    pub nologin: bool,
+    /// user slices that we froze
+    #[serde(default)]
+    pub frozen_slices: Vec<String>,
+    /// user slices that we throttled, with their original properties
+    #[serde(default)]
+    pub throttled_slices: Vec<ThrottledSlice>,
+    /// locked out users that logged in through the soft gate during the current hog
+    #[serde(default)]
+    pub overrides: Vec<HistoryEntry>,
+    /// properties that the throttle module gives to other users
+    #[serde(default)]
+    pub throttle_target: Option<ThrottledSlice>,
+    /// modules that are active under the current hog
+    #[serde(default)]
+    pub active_modules: Vec<Module>,
+    /// state of external modules, by module name. Opaque to us.
+    #[serde(default)]
+    pub module_states: BTreeMap<String, serde_json::Value>,
+}
+
+/// Resource properties of a user slice before we throttled it. u64::MAX means unset. None if the
+/// throttle does not change the property.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ThrottledSlice {
+    pub slice: String,
+    pub cpu_weight: u64,
+    pub io_weight: u64,
+    #[serde(default)]
+    pub cpu_quota_per_sec_usec: Option<u64>,
+    #[serde(default)]
+    pub allowed_cpus: Option<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct AuthPath {
+    pub name: String,
+    pub covered: bool,
+    pub note: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct HistoryEntry {
+    pub time: DateTime<Local>,
+    pub user: String,
+    pub event: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum Operation {
+    Hog,
+    Release,
+    /// let more users in while hogged
+    Allow,
+    /// lock more users out while hogged
+    Deny,
+    /// lock out key files and users that appeared while hogged
+    Rescan,
+}
+
+impl std::fmt::Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Operation::Hog => write!(f, "hog"),
+            Operation::Release => write!(f, "release"),
+            Operation::Allow => write!(f, "allow"),
+            Operation::Deny => write!(f, "deny"),
+            Operation::Rescan => write!(f, "rescan"),
+        }
+    }
+}
+
+/// Parts of a hog that can be selected with `hog --only` and `hog --except`. Each module owns
+/// its part of the DiskState (see `modules::get`).
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(try_from = "String", into = "String")]
+pub enum Module {
+    /// lock other users out (see the backend setting). Owns overmounts, auth_paths, user_coverage and nologin.
+    Ssh,
+    /// stop all active systemd timers. Owns the timers in disabled_systemd_units.
+    Timers,
+    /// stop selected systemd units (e.g. xrdp). Owns the other disabled_systemd_units.
+    Units,
+    /// freeze the processes of other users. Owns frozen_slices.
+    Freeze,
+    /// throttle the resources of other users. Owns throttled_slices.
+    Throttle,
+    /// executable `hosthog-module-<name>` in PATH. Owns its entry in module_states.
+    External(String),
+}
+
+/// modules used by `hog` unless selected otherwise
+pub const DEFAULT_MODULES: [Module; 3] = [Module::Ssh, Module::Timers, Module::Units];
+/// all built-in modules in the order in which they are applied
+pub const ALL_MODULES: [Module; 5] = [Module::Ssh, Module::Timers, Module::Units, Module::Freeze, Module::Throttle];
+
+impl std::fmt::Display for Module {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Module::Ssh => write!(f, "ssh"),
+            Module::Timers => write!(f, "timers"),
+            Module::Units => write!(f, "units"),
+            Module::Freeze => write!(f, "freeze"),
+            Module::Throttle => write!(f, "throttle"),
+            Module::External(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl std::str::FromStr for Module {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Module, String> {
+        match name {
+            "ssh" => Ok(Module::Ssh),
+            "timers" | "systemd-timers" => Ok(Module::Timers),
+            "units" => Ok(Module::Units),
+            "freeze" => Ok(Module::Freeze),
+            "throttle" => Ok(Module::Throttle),
+            _ if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') => {
+                Ok(Module::External(name.to_string()))
+            }
+            _ => Err(format!("invalid module name: {}", name)),
+        }
+    }
+}
+
+impl TryFrom<String> for Module {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Module, String> {
+        name.parse()
+    }
+}
+
+impl From<Module> for String {
+    fn from(module: Module) -> String {
+        module.to_string()
+    }
+}
+
+/// A single change to the system that has been applied by the current transaction.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum Step {
+    /// bind-mounted an overlay over this file
+    Overmount(String),
+    /// stopped this systemd unit
+    StopUnit(String),
+    /// set the hogger. Contains the previous hogger.
+    Hogger(Option<Claim>),
+    /// set the allowed users. Contains the previous ones.
+    AllowedUsers(Vec<String>),
+    /// created /run/nologin
+    Nologin,
+    /// rewrote the users that the nologin backend lets in. Contains the previous content of the
+    /// file, None if it did not exist.
+    NologinAllowedUsers(Option<String>),
+    /// froze this user slice
+    Freeze(String),
+    /// throttled this user slice
+    Throttle(String),
+    /// activated this module
+    Module(Module),
+}
+
+/// Journal of a hog or release. Each step is written to disk as soon as it has been applied.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Transaction {
+    pub operation: Operation,
+    pub started: DateTime<Local>,
+    pub steps: Vec<Step>,
 }
 
 pub fn check_version(state: &DiskState) -> Result<(), String> {
@@ -60,6 +355,15 @@ pub fn load() -> DiskState {
     return state;
 }
 
+/// Load the state without creating it. For callers that must not fail hard.
+pub fn try_load() -> Result<DiskState, String> {
+    if !std::path::Path::new(STATE_FILE.as_str()).is_file() {
+        return Ok(load_default());
+    }
+    let text = std::fs::read_to_string(STATE_FILE.as_str()).map_err(|e| format!("{}: {}", STATE_FILE.as_str(), e))?;
+    serde_json::from_str(&text).map_err(|e| format!("{}: {}", STATE_FILE.as_str(), e))
+}
+
 pub fn store(state: &DiskState) {
     if !users::is_root() {
         panic!("must be root to update hosts hogging state");
@@ -70,7 +374,73 @@ pub fn store(state: &DiskState) {
     if !parent.is_dir() {
         std::fs::create_dir_all(parent).expect("failed to create state directory");
     }
-    std::fs::write(STATE_FILE.as_str(), json).expect("failed to write state file");
+    // readers do not take the lock (pam-check, the gate, the keys command), so they must never
+    // see a partially written file. Replace it atomically instead.
+    let tmp = format!("{}.{}.tmp", STATE_FILE.as_str(), std::process::id());
+    let mut file = std::fs::File::create(&tmp).expect("failed to create temporary state file");
+    file.write_all(json.as_bytes()).expect("failed to write state file");
+    file.sync_all().expect("failed to sync state file");
+    std::fs::rename(&tmp, STATE_FILE.as_str()).expect("failed to replace state file");
+}
+
+/// Exclusive access to the state file until dropped
+pub struct StateLock {
+    _file: std::fs::File,
+}
+
+/// Wait until no other hosthog (e.g. the watcher) modifies the state. Take it before loading a
+/// state that will be stored again, so that no one stores an outdated copy. None if we are not
+/// root, as we can not store the state anyway.
+pub fn lock() -> Option<StateLock> {
+    if !users::is_root() {
+        return None;
+    }
+    std::fs::create_dir_all(util::STATE_PATH).expect("failed to create state directory");
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(LOCK_FILE.as_str())
+        .unwrap_or_else(|e| panic!("can not open {}: {}", LOCK_FILE.as_str(), e));
+    // safe because the file descriptor is open. The lock is released when it is closed.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        panic!("can not lock {}: {}", LOCK_FILE.as_str(), std::io::Error::last_os_error());
+    }
+    Some(StateLock { _file: file })
+}
+
+/// start journaling a hog or release
+pub fn begin(state: &mut DiskState, operation: Operation) {
+    state.transaction = Some(Transaction {
+        operation,
+        started: Local::now(),
+        steps: vec![],
+    });
+    store(state);
+}
+
+/// persist a step that has just been applied to the system
+pub fn journal(state: &mut DiskState, step: Step) {
+    if let Some(transaction) = &mut state.transaction {
+        transaction.steps.push(step);
+    }
+    store(state);
+}
+
+/// mark the current transaction as completed
+pub fn commit(state: &mut DiskState) {
+    state.transaction = None;
+    store(state);
+}
+
+/// record an event caused by the current user in the history
+pub fn log(state: &mut DiskState, event: String) {
+    let user = users::my_username().unwrap_or_else(|| String::from("<unknown>"));
+    state.history.push(HistoryEntry {
+        time: Local::now(),
+        user,
+        event,
+    });
 }
 
 pub fn load_default() -> DiskState {
@@ -83,9 +453,31 @@ pub fn load_default() -> DiskState {
                 String::from("%h/.ssh/authorized_keys"),
                 String::from("/etc/ssh/authorized_keys.d/%u"),
             ],
+            pam_services: default_pam_services(),
+            backend: Backend::default(),
+            gate: Gate::default(),
+            lockout_uids: default_lockout_uids(),
+            lockout_skip_shells: default_lockout_skip_shells(),
+            lockout_exclude: vec![],
+            exempt_users: default_exempt_users(),
+            home_roots: default_home_roots(),
         },
         disabled_systemd_units: vec![],
         state_version: DEFAULT_STATE_VERSION,
+        transaction: None,
+        history: vec![],
+        allowed_users: vec![],
+        auth_paths: vec![],
+        user_coverage: vec![],
+        wrapped_commands: vec![],
+        principals_files: vec![],
+        nologin: false,
+        frozen_slices: vec![],
+        throttled_slices: vec![],
+        throttle_target: None,
+        overrides: vec![],
+        active_modules: vec![],
+        module_states: BTreeMap::new(),
     };
 
     return state;
@@ -93,33 +485,105 @@ pub fn load_default() -> DiskState {
 
 use crate::hog;
 
-pub fn expand_authorized_keys_file(settings: &Settings, users: Vec<hog::User>) -> Vec<String> {
+/// Substitute sshd's %-tokens (see TOKENS in man sshd_config). `tokens` maps each supported token
+/// character to its replacement. `%%` always expands to a literal `%`.
+pub fn expand_tokens(pattern: &str, tokens: &[(char, &str)]) -> Result<String, String> {
+    let mut expanded = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('%') => expanded.push('%'),
+            Some(token) => match tokens.iter().find(|(t, _)| *t == token) {
+                Some((_, value)) => expanded.push_str(value),
+                None => return Err(format!("unknown token %{} in {}", token, pattern)),
+            },
+            None => return Err(format!("incomplete token at the end of {}", pattern)),
+        }
+    }
+    Ok(expanded)
+}
+
+/// Check that a pattern only uses tokens that sshd understands in AuthorizedKeysFile
+pub fn validate_authorized_keys_file(pattern: &str) -> Result<(), String> {
+    expand_tokens(pattern, &[('h', ""), ('u', ""), ('U', "")]).map(|_| ())
+}
+
+/// Expand an AuthorizedKeysFile pattern for one user like sshd does: substitute %%, %h, %u and
+/// %U. After expansion, relative paths are relative to the user's home directory. The special
+/// value `none` disables key files.
+pub fn expand_authorized_keys_file_for(pattern: &str, user: &hog::User) -> Result<Option<String>, String> {
+    if pattern == "none" {
+        return Ok(None);
+    }
+    let uid = user.uid.to_string();
+    let file = expand_tokens(pattern, &[('h', &user.home), ('u', &user.name), ('U', &uid)])?;
+    if file.starts_with('/') {
+        Ok(Some(file))
+    } else {
+        Ok(Some(format!("{}/{}", user.home.trim_end_matches('/'), file)))
+    }
+}
+
+/// Expand the AuthorizedKeysFile patterns of the settings and the ones that sshd uses for each
+/// user, with the user they belong to. Warns where the settings and sshd disagree about the files
+/// of a user.
+pub fn expand_authorized_keys_file<'a>(settings: &Settings, sshd: &SshdConfig, users: &'a [hog::User]) -> Vec<(&'a hog::User, String)> {
     let mut files = vec![];
+    // pattern -> number of users
+    let mut missing: BTreeMap<String, usize> = BTreeMap::new();
+    let mut unread: BTreeMap<String, usize> = BTreeMap::new();
     for user in users {
-        for file in &settings.authorized_keys_file {
-            // replace %h with home directory
-            let file = file.replacen("%h", &user.home, 1);
-            // replace %u with username
-            let file = file.replacen("%u", &user.name, 1);
-            files.push(file);
-            // TODO other replacements and respect escaped %: %%
+        let expand = |patterns: &[String]| -> Vec<(String, String)> {
+            patterns
+                .iter()
+                .filter_map(|pattern| match expand_authorized_keys_file_for(pattern, user) {
+                    Ok(file) => file.map(|file| (pattern.clone(), file)),
+                    Err(e) => {
+                        eprintln!("WARN: skipping authorized_keys_file of {}: {}", user.name, e);
+                        None
+                    }
+                })
+                .collect()
+        };
+        let from_settings = expand(&settings.authorized_keys_file);
+        let from_sshd = expand(&sshd.authorized_keys_files(user));
+        for (pattern, file) in &from_sshd {
+            if !from_settings.iter().any(|(_, f)| f == file) {
+                *missing.entry(pattern.clone()).or_default() += 1;
+            }
+        }
+        for (pattern, file) in &from_settings {
+            if sshd.known() && !from_sshd.iter().any(|(_, f)| f == file) && std::path::Path::new(file).exists() {
+                *unread.entry(pattern.clone()).or_default() += 1;
+            }
         }
+        for (_, file) in from_settings.into_iter().chain(from_sshd) {
+            if !files.contains(&(user, file.clone())) {
+                files.push((user, file));
+            }
+        }
+    }
+    for (pattern, users) in missing {
+        eprintln!("WARN: sshd uses AuthorizedKeysFile {} for {} users, but the authorized_keys_file setting does not contain it. Hogging it anyway.", pattern, users);
+    }
+    for (pattern, users) in unread {
+        eprintln!("WARN: the authorized_keys_file setting contains {}, which exists for {} users but sshd does not read it. Hogging it anyway.", pattern, users);
     }
-    return files;
+    files
 }
 
-/// remove all claims that have timed out
-pub fn maintenance(state: &mut DiskState, needs_release: &mut bool) {
+/// claims whose timeout has passed
+pub fn expired_claims(state: &DiskState) -> Vec<Claim> {
     let now = Local::now();
-    let mut new_claims = vec![];
-    let mut dropped_claims = vec![];
-    for claim in &state.claims {
-        if claim.timeout > now {
-            new_claims.push(claim.clone());
-        } else {
-            dropped_claims.push(claim.clone());
-        }
-    }
+    state.claims.iter().filter(|claim| claim.timeout <= now).cloned().collect()
+}
+
+pub fn maintenance(state: &mut DiskState, needs_release: &mut bool) {
+    let dropped_claims = expired_claims(state);
     if let Some(hogger) = &state.hogger {
         for claim in &dropped_claims {
             if claim == hogger {
@@ -128,6 +592,62 @@ pub fn maintenance(state: &mut DiskState, needs_release: &mut bool) {
             }
         }
     }
-    state.claims = new_claims;
+    state.claims.retain(|claim| !dropped_claims.contains(claim));
     println!("Maintenance: {} claims expired, {} hogs released", dropped_claims.len(), if *needs_release { 1 } else { 0 });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alice() -> hog::User {
+        hog::User {
+            name: String::from("alice"),
+            uid: 1001,
+            gid: 1001,
+            home: String::from("/home/alice"),
+            shell: String::from("/bin/bash"),
+        }
+    }
+
+    fn expand(pattern: &str) -> Result<Option<String>, String> {
+        expand_authorized_keys_file_for(pattern, &alice())
+    }
+
+    #[test]
+    fn expands_tokens() {
+        assert_eq!(expand("%h/.ssh/authorized_keys"), Ok(Some(String::from("/home/alice/.ssh/authorized_keys"))));
+        assert_eq!(expand("/etc/ssh/authorized_keys.d/%u"), Ok(Some(String::from("/etc/ssh/authorized_keys.d/alice"))));
+        assert_eq!(expand("/etc/ssh/keys/%U"), Ok(Some(String::from("/etc/ssh/keys/1001"))));
+        assert_eq!(expand("/srv/%u/%U/keys"), Ok(Some(String::from("/srv/alice/1001/keys"))));
+    }
+
+    #[test]
+    fn expands_percent() {
+        assert_eq!(expand("/etc/ssh/100%%/%u"), Ok(Some(String::from("/etc/ssh/100%/alice"))));
+        assert_eq!(expand("/etc/ssh/%%u"), Ok(Some(String::from("/etc/ssh/%u"))));
+    }
+
+    #[test]
+    fn relative_paths_are_in_home() {
+        assert_eq!(expand(".ssh/authorized_keys"), Ok(Some(String::from("/home/alice/.ssh/authorized_keys"))));
+        assert_eq!(expand("keys/%u"), Ok(Some(String::from("/home/alice/keys/alice"))));
+        let mut root = alice();
+        root.home = String::from("/");
+        assert_eq!(expand_authorized_keys_file_for(".ssh/authorized_keys", &root), Ok(Some(String::from("/.ssh/authorized_keys"))));
+    }
+
+    #[test]
+    fn rejects_unknown_tokens() {
+        assert!(expand("/etc/ssh/%d/keys").is_err());
+        assert!(expand("/etc/ssh/keys%").is_err());
+        assert!(validate_authorized_keys_file("%h/.ssh/%x").is_err());
+        assert!(validate_authorized_keys_file("%h/.ssh/authorized_keys").is_ok());
+    }
+
+    #[test]
+    fn none_disables_key_files() {
+        assert_eq!(expand("none"), Ok(None));
+        assert_eq!(expand("/etc/ssh/none"), Ok(Some(String::from("/etc/ssh/none"))));
+    }
+}
@@ -0,0 +1,160 @@
+use crate::diskstate::{self, DiskState, Module, Settings};
+use crate::evict;
+use crate::hog;
+use crate::util;
+use crate::modules;
+use crate::users;
+use crate::HogCommand;
+use serde::Serialize;
+
+/// What a command would do
+#[derive(Serialize)]
+pub struct Plan {
+    pub operation: String,
+    /// changes to claims and the hog itself
+    pub notes: Vec<String>,
+    /// only for hogs that lock users out
+    pub locked_out_users: Vec<LockedOutUser>,
+    pub modules: Vec<ModulePlan>,
+    /// only for hogs with --evict: the sessions that would be terminated after the grace period
+    pub evicted_sessions: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct LockedOutUser {
+    pub name: String,
+    pub logged_in: bool,
+}
+
+#[derive(Serialize)]
+pub struct ModulePlan {
+    pub module: Module,
+    pub actions: Vec<String>,
+}
+
+pub fn print(plan: &Plan, json: bool) {
+    if json {
+        println!("{}", serde_json::to_string_pretty(plan).expect("can not serialize plan"));
+        return;
+    }
+    println!("Dry run of {}, nothing is changed.", plan.operation);
+    for note in &plan.notes {
+        println!("{}", note);
+    }
+    if !plan.locked_out_users.is_empty() {
+        println!("Users that would be locked out:");
+        for user in &plan.locked_out_users {
+            match user.logged_in {
+                true => println!("  {} (logged in)", user.name),
+                false => println!("  {}", user.name),
+            }
+        }
+    }
+    if !plan.evicted_sessions.is_empty() {
+        println!("Sessions that would be terminated:");
+        for session in &plan.evicted_sessions {
+            println!("  {}", session);
+        }
+    }
+    for module in &plan.modules {
+        println!("Module {}:", module.module);
+        if module.actions.is_empty() {
+            println!("  nothing to do");
+        }
+        for action in &module.actions {
+            println!("  {}", action);
+        }
+    }
+}
+
+fn locked_out_users(settings: &Settings, allowed_users: &[String]) -> Vec<LockedOutUser> {
+    let logged_in = users::logged_in();
+    hog::all_users(settings)
+        .into_iter()
+        .filter(|user| settings.locks_out(user) && !allowed_users.contains(&user.name))
+        .map(|user| LockedOutUser { logged_in: logged_in.contains(&user.name), name: user.name })
+        .collect()
+}
+
+pub fn hog(cmd: &HogCommand, state: &DiskState) -> Plan {
+    let (selected, allowed_users, claim) = hog::prepare_hog(cmd, state);
+    // the modules see the state as it would be during the hog
+    let mut hogged = state.clone();
+    hogged.allowed_users = allowed_users.clone();
+    hogged.hogger = Some(claim);
+
+    let mut notes = vec![format!("allowed users: {}", allowed_users.join(", "))];
+    let active: Vec<String> = selected.iter().filter(|m| state.active_modules.contains(m)).map(|m| m.to_string()).collect();
+    if !active.is_empty() {
+        notes.push(format!("already hogged: {}", active.join(", ")));
+    }
+    let locked_out_users = match selected.contains(&Module::Ssh) {
+        true => locked_out_users(&state.settings, &allowed_users),
+        false => vec![],
+    };
+    let modules = selected
+        .into_iter()
+        .filter(|m| !state.active_modules.contains(m))
+        .map(|module| ModulePlan { actions: modules::get(&module).dry_run(&hogged, cmd), module })
+        .collect();
+    let mut evicted_sessions = vec![];
+    if let Some(grace) = cmd.evict {
+        let grace = util::format_timeout(chrono::Duration::from_std(grace).unwrap());
+        match evict::planned(&hogged) {
+            Ok(sessions) if sessions.is_empty() => notes.push(format!("evict: no sessions of other users to terminate after {}", grace)),
+            Ok(sessions) => {
+                notes.push(format!("evict: warn other users and terminate their {} sessions after {}", sessions.len(), grace));
+                evicted_sessions = sessions;
+            }
+            Err(e) => notes.push(format!("evict: terminate the sessions of other users after {} ({})", grace, e)),
+        }
+    }
+    Plan { operation: String::from("hog"), notes, locked_out_users, modules, evicted_sessions }
+}
+
+/// what reverting the modules would undo
+fn revert(state: &DiskState, selected: Vec<Module>) -> Vec<ModulePlan> {
+    selected
+        .into_iter()
+        .map(|module| ModulePlan { actions: modules::get(&module).leftovers(state), module })
+        .filter(|plan| !plan.actions.is_empty() || state.active_modules.contains(&plan.module))
+        .collect()
+}
+
+pub fn release(state: &DiskState, only: &[Module]) -> Plan {
+    let mut notes = vec![];
+    if only.is_empty() {
+        if let Some(hogger) = &state.hogger {
+            notes.push(format!("end the hog of {}", hogger.user));
+        }
+        if let Some(me) = users::my_username() {
+            let claims = state.claims.iter().filter(|claim| claim.user == me && claim.exclusive).count();
+            if claims > 0 {
+                notes.push(format!("remove {} exclusive claims of {}", claims, me));
+            }
+        }
+    }
+    let modules = revert(state, hog::release_selection(state, only));
+    Plan { operation: String::from("release"), notes, locked_out_users: vec![], modules, evicted_sessions: vec![] }
+}
+
+pub fn maintenance(state: &DiskState) -> Plan {
+    let mut notes = vec![];
+    if let Some(transaction) = &state.transaction {
+        notes.push(format!("recover the unfinished {} started at {} ({} steps)", transaction.operation, transaction.started, transaction.steps.len()));
+    }
+    let expired = diskstate::expired_claims(state);
+    for claim in &expired {
+        notes.push(format!("remove the expired claim of {}", claim.user));
+    }
+    let modules = match &state.hogger {
+        Some(hogger) if expired.contains(hogger) => {
+            notes.push(format!("release the hog of {}", hogger.user));
+            revert(state, hog::release_selection(state, &[]))
+        }
+        Some(_) => vec![],
+        // reconcile: clean up what is left without a hog
+        None => revert(state, modules::reconcilable(state)),
+    };
+    Plan { operation: String::from("maintenance"), notes, locked_out_users: vec![], modules, evicted_sessions: vec![] }
+}
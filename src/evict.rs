@@ -0,0 +1,131 @@
+use crate::diskstate::{self, DiskState};
+use crate::hog;
+use crate::users;
+use crate::util;
+use zbus_systemd::zbus;
+
+type ExResult<T> = Result<T, Box<dyn std::error::Error + 'static>>;
+
+#[derive(Debug)]
+struct Session {
+    id: String,
+    user: String,
+    tty: String,
+    remote_host: String,
+    service: String,
+}
+
+impl std::fmt::Display for Session {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "session {} of {} ({}", self.id, self.user, self.service)?;
+        if !self.tty.is_empty() {
+            write!(f, " on {}", self.tty)?;
+        }
+        if !self.remote_host.is_empty() {
+            write!(f, " from {}", self.remote_host)?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// logind session that this process runs in
+fn my_session() -> Option<String> {
+    std::fs::read_to_string("/proc/self/sessionid").ok().map(|id| id.trim().to_string())
+}
+
+/// sessions of users that the hog locks out
+async fn other_sessions(state: &DiskState) -> ExResult<Vec<Session>> {
+    let conn = zbus::Connection::system().await?;
+    let manager = zbus_systemd::login1::ManagerProxy::new(&conn).await?;
+    let me = my_session();
+    let mut sessions = vec![];
+    for (id, _uid, user, _seat, path) in manager.list_sessions().await? {
+        // e.g. service accounts and greeters, or users in lockout_exclude or exempt_users
+        let locked_out = hog::get_user(&user).is_some_and(|u| state.settings.locks_out(&u));
+        if !locked_out || state.allowed_users.contains(&user) || Some(&id) == me.as_ref() {
+            continue;
+        }
+        let session = zbus_systemd::login1::SessionProxy::new(&conn, path).await?;
+        sessions.push(Session {
+            id,
+            user,
+            tty: session.tty().await.unwrap_or_default(),
+            remote_host: session.remote_host().await.unwrap_or_default(),
+            service: session.service().await.unwrap_or_default(),
+        });
+    }
+    Ok(sessions)
+}
+
+async fn terminate(id: &str) -> ExResult<()> {
+    let conn = zbus::Connection::system().await?;
+    let manager = zbus_systemd::login1::ManagerProxy::new(&conn).await?;
+    manager.terminate_session(id.to_string()).await?;
+    Ok(())
+}
+
+/// The sessions that an eviction would terminate now, for dry runs
+pub fn planned(state: &DiskState) -> Result<Vec<String>, String> {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let sessions = rt.block_on(other_sessions(state)).map_err(|e| format!("can not list logind sessions: {}", e))?;
+    Ok(sessions.iter().map(|session| session.to_string()).collect())
+}
+
+/// parse the grace period of `hog --evict`
+pub fn parse_grace(grace: &str) -> Result<std::time::Duration, String> {
+    let parsed = duration_str::parse(grace).map_err(|e| format!("can not parse grace period {}: {}", grace, e))?;
+    // we format it as a chrono::Duration later on
+    chrono::Duration::from_std(parsed).map_err(|e| format!("grace period {} is too long: {}", grace, e))?;
+    Ok(parsed)
+}
+
+/// Warn all sessions of other users, wait for `grace` and terminate the ones that are left.
+/// Releases `lock` while waiting and reloads the state afterwards.
+pub fn do_evict(state: &mut DiskState, grace: std::time::Duration, lock: &mut Option<diskstate::StateLock>) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let sessions = rt.block_on(other_sessions(state)).expect("Can't list logind sessions");
+    if sessions.is_empty() {
+        println!("evict: no sessions of other users");
+        return;
+    }
+
+    let deadline = util::format_timeout(chrono::Duration::from_std(grace).unwrap());
+    let mut message = match &state.hogger {
+        Some(claim) => hog::ssh_hogged_message(claim) + "\n",
+        None => String::new(),
+    };
+    message += &format!("Your session will be terminated in {}. Please save your work and log out.", deadline);
+    let mut notified: Vec<&String> = vec![];
+    for session in &sessions {
+        if !notified.contains(&&session.user) {
+            users::notify_user(&session.user, &message);
+            notified.push(&session.user);
+        }
+    }
+    println!("evict: waiting {} for {} sessions of other users to end", deadline, sessions.len());
+    // do not block the watcher and other commands while we wait
+    diskstate::store(state);
+    let locked = lock.take().is_some();
+    std::thread::sleep(grace);
+    if locked {
+        *lock = diskstate::lock();
+        *state = diskstate::load();
+    }
+    if state.hogger.is_none() {
+        println!("evict: the host has been released in the meantime, not terminating any sessions");
+        return;
+    }
+
+    // users may have logged out (or other sessions appeared) in the meantime
+    let sessions = rt.block_on(other_sessions(state)).expect("Can't list logind sessions");
+    for session in sessions {
+        match rt.block_on(terminate(&session.id)) {
+            Ok(_) => {
+                println!("evicted {}", session);
+                diskstate::log(state, format!("evicted {}", session));
+                diskstate::store(state);
+            }
+            Err(e) => println!("WARN: failed to evict {}: {}", session, e),
+        }
+    }
+}
@@ -0,0 +1,189 @@
+use crate::diskstate::{self, DiskState, HistoryEntry};
+use crate::hog;
+use crate::users;
+use crate::util;
+use chrono::prelude::*;
+use std::fs;
+use std::ffi::CString;
+use std::io::{BufRead, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+
+/// what locked out users have to type to get a shell anyway
+const PHRASE: &str = "I need this host, notify the owner";
+/// The gate runs via sudo while the user logs in and does not take the state lock. It appends
+/// overrides here instead and the next maintenance imports them. Only root may write it.
+const OVERRIDES_FILE: &str = "/run/hosthog/overrides";
+/// where `import` moves the overrides file before reading it
+const IMPORTING_FILE: &str = "/run/hosthog/overrides.importing";
+
+/// create the file that the gate records overrides in
+pub fn prepare() {
+    let parent = Path::new(OVERRIDES_FILE).parent().unwrap();
+    fs::create_dir_all(parent).expect("failed to create /run/hosthog");
+    if !Path::new(OVERRIDES_FILE).exists() {
+        fs::write(OVERRIDES_FILE, "").expect("failed to create overrides file");
+    }
+    fs::set_permissions(OVERRIDES_FILE, fs::Permissions::from_mode(0o600)).expect("failed to set permissions of overrides file");
+}
+
+fn read_overrides(file: &str) -> Vec<HistoryEntry> {
+    let text = fs::read_to_string(file).unwrap_or_default();
+    text.lines().filter_map(|line| serde_json::from_str(line).ok()).collect()
+}
+
+/// overrides that the gate recorded and that are not imported yet
+pub fn pending() -> Vec<HistoryEntry> {
+    let mut overrides = read_overrides(IMPORTING_FILE);
+    overrides.extend(read_overrides(OVERRIDES_FILE));
+    overrides
+}
+
+/// move recorded overrides into the state and its history
+pub fn import(state: &mut DiskState) {
+    // move the file away first, so that overrides that are recorded meanwhile go to a new file.
+    // A file left over by an interrupted import is read as well.
+    if let Err(e) = fs::rename(OVERRIDES_FILE, IMPORTING_FILE) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            println!("WARN: could not import {}: {}", OVERRIDES_FILE, e);
+        }
+    }
+    let overrides = read_overrides(IMPORTING_FILE);
+    for entry in &overrides {
+        println!("WARN: {} overrode the hog at {}", entry.user, entry.time);
+        state.history.push(entry.clone());
+        state.overrides.push(entry.clone());
+    }
+    if !overrides.is_empty() {
+        diskstate::store(state);
+    }
+    if let Err(e) = fs::remove_file(IMPORTING_FILE) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            println!("WARN: could not remove {}: {}", IMPORTING_FILE, e);
+        }
+    }
+}
+
+/// remove the overrides file once the gate is gone
+pub fn release(state: &mut DiskState) {
+    import(state);
+    if let Err(e) = fs::remove_file(OVERRIDES_FILE) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            println!("WARN: could not remove {}: {}", OVERRIDES_FILE, e);
+        }
+    }
+}
+
+fn record(me: &str, hogger: &str) -> Result<(), String> {
+    let entry = HistoryEntry {
+        time: Local::now(),
+        user: me.to_string(),
+        event: format!("override: logged in through the soft gate despite the hog of {}", hogger),
+    };
+    let line = serde_json::to_string(&entry).map_err(|e| e.to_string())? + "\n";
+    fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(OVERRIDES_FILE)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .map_err(|e| format!("{}: {}", OVERRIDES_FILE, e))?;
+    let _ = Command::new("logger").args(["-p", "auth.warning", "-t", "hosthog", &entry.event.replace("override:", me)]).status();
+    Ok(())
+}
+
+/// The user that logs in. sshd runs the gate via sudo.
+fn caller() -> String {
+    // safe because getuid only returns a number
+    let uid = unsafe { libc::getuid() };
+    match std::env::var("SUDO_UID").ok().and_then(|uid| uid.parse().ok()) {
+        Some(sudo_uid) if uid == 0 => util::get_username(sudo_uid),
+        _ => util::get_username(uid),
+    }
+}
+
+/// Drop the root privileges of sudo and take on the identity of `user`
+fn become_user(user: &hog::User) -> Result<(), String> {
+    let name = CString::new(user.name.as_str()).map_err(|e| e.to_string())?;
+    // safe because name is a valid C string. initgroups and setgid need root, so setuid comes last.
+    let dropped = unsafe { libc::initgroups(name.as_ptr(), user.gid) == 0 && libc::setgid(user.gid) == 0 && libc::setuid(user.uid) == 0 };
+    if !dropped {
+        return Err(std::io::Error::last_os_error().to_string());
+    }
+    for (key, value) in [("HOME", &user.home), ("USER", &user.name), ("LOGNAME", &user.name), ("SHELL", &user.shell)] {
+        std::env::set_var(key, value);
+    }
+    for key in ["SUDO_COMMAND", "SUDO_USER", "SUDO_UID", "SUDO_GID"] {
+        std::env::remove_var(key);
+    }
+    let _ = std::env::set_current_dir(&user.home);
+    Ok(())
+}
+
+/// replace this process with the shell of the user, running the command that ssh was asked for
+fn exec_shell(me: &str, command: Option<String>) -> ! {
+    let user = hog::get_user(me);
+    // safe because geteuid only returns a number
+    if unsafe { libc::geteuid() } == 0 && me != "root" {
+        let dropped = match &user {
+            Some(user) => become_user(user),
+            None => Err(format!("unknown user {}", me)),
+        };
+        if let Err(e) = dropped {
+            eprintln!("hosthog: can not switch to {}: {}", me, e);
+            std::process::exit(1);
+        }
+    }
+    let shell = user.map(|u| u.shell).filter(|s| !s.is_empty()).unwrap_or_else(|| String::from("/bin/sh"));
+    let err = match command.filter(|c| !c.is_empty()) {
+        Some(command) => Command::new(&shell).arg("-c").arg(command).exec(),
+        None => {
+            let name = Path::new(&shell).file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+            // a leading dash makes it a login shell
+            Command::new(&shell).arg0(format!("-{}", name)).exec()
+        }
+    };
+    eprintln!("hosthog: can not run {}: {}", shell, err);
+    std::process::exit(1);
+}
+
+/// Forced command for locked out users with the soft gate. Shows the hog and lets them in after a
+/// confirmation, which notifies the hogger. `command` is what ssh was asked to run.
+pub fn do_gate(command: Option<String>) -> ! {
+    let me = caller();
+    let state = match diskstate::try_load() {
+        Ok(state) => state,
+        Err(_) => exec_shell(&me, command),
+    };
+    let hogger = match &state.hogger {
+        Some(hogger) if hog::locked_out(&state, &me) => hogger.clone(),
+        _ => exec_shell(&me, command),
+    };
+
+    eprintln!("{}", hog::ssh_hogged_message(&hogger));
+    // safe because isatty only looks at the file descriptor
+    if unsafe { libc::isatty(0) } != 1 {
+        eprintln!("Log in interactively to override the hog.");
+        std::process::exit(1);
+    }
+    eprintln!();
+    eprintln!("If you really need this host, type `{}` to log in anyway:", PHRASE);
+    let mut answer = String::new();
+    if std::io::stdin().lock().read_line(&mut answer).is_err() || answer.trim() != PHRASE {
+        eprintln!("Not confirmed. Bye.");
+        std::process::exit(1);
+    }
+
+    if let Err(e) = record(&me, &hogger.user) {
+        eprintln!("hosthog: can not record the override, not letting you in: {}", e);
+        std::process::exit(1);
+    }
+    match users::write_to_user(&hogger.user, &format!("hosthog: {} overrode your hog and logged in.", me)) {
+        true => eprintln!("{} has been notified.", hogger.user),
+        false => eprintln!("{} could not be notified (not logged in?). The override is in the history of the hog.", hogger.user),
+    }
+    exec_shell(&me, command)
+}
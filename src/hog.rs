@@ -1,12 +1,29 @@
 use nix;
-use crate::diskstate;
+use crate::diskstate::{self, Module, ALL_MODULES, DEFAULT_MODULES};
+use crate::modules::{self, HogModule};
+use crate::pam;
 use crate::systemd_units;
+use crate::sshd_config;
+use crate::authorized_keys;
+use crate::keys_command;
+use crate::nologin;
+use crate::evict;
+use crate::gate;
+use crate::watch;
+use crate::namespace;
+use crate::mountinfo::{Mount, MountTable};
 use crate::users;
+use crate::HogCommand;
 use once_cell::sync::Lazy;
 use crate::util;
 use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::panic;
 
-static OVERLAY_PATH: Lazy<String> = Lazy::new(|| format!("{}/overlay", util::STATE_PATH));
+pub static OVERLAY_PATH: Lazy<String> = Lazy::new(|| format!("{}/overlay", util::STATE_PATH));
+/// overlays that were edited while hogged are kept here after the release
+pub static EDITS_PATH: Lazy<String> = Lazy::new(|| format!("{}/edits", util::STATE_PATH));
 
 pub fn ssh_hogged_message(claim: &diskstate::Claim) -> String {
     let duration = util::format_timeout_abs(claim.timeout);
@@ -17,8 +34,13 @@ pub fn ssh_hogged_message(claim: &diskstate::Claim) -> String {
     ].join("\n")
 }
 
-fn ssh_hogged_command() -> String {
-    format!("sudo {} status", util::prog())
+/// The command that keys of locked out users are restricted to, and whether they get a pty for it
+pub fn ssh_hogged_command(settings: &diskstate::Settings) -> (String, bool) {
+    match settings.gate {
+        diskstate::Gate::Hard => (format!("sudo {} status", util::prog()), false),
+        // the user's shell expands the variable when sshd runs the forced command
+        diskstate::Gate::Soft => (format!("sudo {} gate --command=\"$SSH_ORIGINAL_COMMAND\"", util::prog()), true),
+    }
 }
 
 fn escape(input: &str) -> String {
@@ -29,45 +51,146 @@ fn escape(input: &str) -> String {
     }).collect()
 }
 
-fn overmount(file: &str) -> Result<(), Option<nix::errno::Errno>> {
-    if !std::path::Path::new(file).is_file() {
-        return Err(None);
+pub fn overlay_file(file: &str) -> String {
+    format!("{}/{}", OVERLAY_PATH.as_str(), escape(file))
+}
+
+pub fn bind_mount(source: &str, target: &str) -> Result<(), nix::errno::Errno> {
+    nix::mount::mount(
+        Some(source),
+        target,
+        None::<&str>,
+        nix::mount::MsFlags::MS_BIND,
+        None::<&str>
+    )
+}
+
+/// Read a file, as its owner if root may not read it (NFS homes with root_squash). None if it
+/// does not exist.
+fn read_key_file(file: &str, owner: Option<&User>) -> Result<Option<String>, String> {
+    let err = match fs::read_to_string(file) {
+        Ok(text) => return Ok(Some(text)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => err,
+    };
+    let owner = match owner {
+        Some(owner) if err.kind() == std::io::ErrorKind::PermissionDenied => owner,
+        _ => return Err(err.to_string()),
+    };
+    let out = std::process::Command::new("setpriv")
+        .args([format!("--reuid={}", owner.uid), format!("--regid={}", owner.gid), String::from("--init-groups")])
+        .args(["sh", "-c", "test -e \"$1\" || exit 3; cat \"$1\"", "sh", file])
+        .output()
+        .map_err(|e| format!("{} (reading it as {} failed: {})", err, owner.name, e))?;
+    match out.status.code() {
+        Some(0) => Ok(Some(String::from_utf8_lossy(&out.stdout).into_owned())),
+        Some(3) => Ok(None),
+        _ => Err(format!("{} (also as {}: {})", err, owner.name, String::from_utf8_lossy(&out.stderr).trim())),
     }
+}
 
-    let authorized_keys: String = fs::read_to_string(file).expect("foo");
-    let overlay_keys = authorized_keys.lines().map(|line| 
-        if !line.is_empty() {
-            format!("restrict,command=\"{}\" {}", ssh_hogged_command(), line)
-        } else {
-            String::from(line)
-        }
-    ).collect::<Vec<String>>().join("\n");
-    let overlay_file = format!("{}/{}", OVERLAY_PATH.as_str(), escape(file));
-    fs::create_dir_all(OVERLAY_PATH.as_str()).expect("foo2");
-    fs::write(overlay_file.as_str(), overlay_keys).expect("foo1");
+/// whether `file` exists, or root may not look (NFS homes with root_squash)
+fn may_exist(file: &str) -> bool {
+    match fs::metadata(file) {
+        Ok(meta) => meta.is_file(),
+        Err(err) => err.kind() == std::io::ErrorKind::PermissionDenied,
+    }
+}
 
-    match nix::mount::mount(
-        // Some("/dev/null"), 
-        Some(overlay_file.as_str()), 
-        file,
-        None::<&str>, 
-        nix::mount::MsFlags::MS_BIND, 
-        None::<&str>
-    ) {
+/// bind-mount a rewritten copy of `file` over it. Err(None) if it does not exist.
+fn overmount(file: &str, owner: Option<&User>, rewrite: impl Fn(&str) -> String) -> Result<(), Option<String>> {
+    let original = match read_key_file(file, owner) {
+        Ok(Some(original)) => original,
+        Ok(None) => return Err(None),
+        Err(err) => return Err(Some(err)),
+    };
+    let overlay_keys = rewrite(&original);
+    let overlay_file = overlay_file(file);
+    fs::create_dir_all(OVERLAY_PATH.as_str()).map_err(|e| Some(format!("can not create {}: {}", OVERLAY_PATH.as_str(), e)))?;
+    fs::write(overlay_file.as_str(), overlay_keys).map_err(|e| Some(format!("can not write {}: {}", overlay_file, e)))?;
+
+    match bind_mount(overlay_file.as_str(), file) {
         Ok(_) => {},
-        Err(err) => { return Err(Some(err)); },
+        Err(err) => {
+            let _ = fs::remove_file(&overlay_file);
+            return Err(Some(format!("can not mount over it: {}", err)));
+        },
     }
 
     return Ok(());
 }
 
-#[derive(Debug)]
+/// Overmount all files that are not overmounted yet. Returns (overmounted, skipped, failed files
+/// with the reason). Files that someone else mounted over are not restricted, so they fail.
+/// `principals` tells whether the files are AuthorizedPrincipalsFiles.
+fn overmount_all(files: Vec<String>, owners: &[(User, Vec<String>)], mounts: &MountTable, principals: bool, rewrite: impl Fn(&str) -> String, state: &mut diskstate::DiskState) -> (usize, usize, Vec<(String, String)>) {
+    let mut overmounted = 0;
+    let mut skipped = 0;
+    let mut failed = vec![];
+    for file in &files {
+        // files that we recorded as overmounted
+        if state.overmounts.contains(file) {
+            skipped += 1;
+            continue;
+        }
+        if let Some(other) = mounts.at(file).last() {
+            failed.push((file.clone(), format!("mounted over by {}", other.describe())));
+            continue;
+        }
+        let owner = owners.iter().find(|(_, files)| files.contains(file)).map(|(user, _)| user);
+        match overmount(file, owner, &rewrite) {
+            Ok(_) => {
+                overmounted += 1;
+                state.overmounts.push(file.clone());
+                if principals {
+                    state.principals_files.push(file.clone());
+                }
+                diskstate::journal(state, diskstate::Step::Overmount(file.clone()));
+            },
+            Err(None) => { }, // ignore files that dont exist
+            Err(Some(err)) => { failed.push((file.clone(), err)); },
+        }
+    }
+    (overmounted, skipped, failed)
+}
+
+#[derive(Debug, PartialEq)]
 pub struct User {
     pub name: String,
+    pub uid: u32,
+    pub gid: u32,
     pub home: String,
+    pub shell: String,
+}
+
+/// read a passwd entry. None if it lacks a name or home.
+fn user_from_passwd(passwd: &libc::passwd) -> Option<User> {
+    if passwd.pw_dir.is_null() || passwd.pw_name.is_null() {
+        return None;
+    }
+    // safe because we null check before accessing it
+    let home = unsafe { std::ffi::CStr::from_ptr(passwd.pw_dir).to_string_lossy().into_owned() };
+    // safe because we null check before accessing it
+    let name = unsafe { std::ffi::CStr::from_ptr(passwd.pw_name).to_string_lossy().into_owned() };
+    // safe because we null check before accessing it
+    let shell = match passwd.pw_shell.is_null() {
+        true => String::new(),
+        false => unsafe { std::ffi::CStr::from_ptr(passwd.pw_shell).to_string_lossy().into_owned() },
+    };
+    Some(User { name, uid: passwd.pw_uid, gid: passwd.pw_gid, home, shell })
 }
 
-fn list_users() -> Vec<User> {
+pub fn get_user(name: &str) -> Option<User> {
+    let name = std::ffi::CString::new(name).ok()?;
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return None;
+    }
+    // safe because we null check before accessing it
+    user_from_passwd(unsafe { &*passwd })
+}
+
+pub fn list_users() -> Vec<User> {
     let mut users = vec![];
 
     loop {
@@ -77,14 +200,9 @@ fn list_users() -> Vec<User> {
             if passwd.is_null() { break };
             *passwd
         };
-        if passwd.pw_dir.is_null() { continue };
-        // safe because we null check before accessing it
-        let home = unsafe { std::ffi::CStr::from_ptr(passwd.pw_dir).to_string_lossy().into_owned() };
-        // safe because we null check before accessing it
-        if passwd.pw_name.is_null() { continue };
-        let name = unsafe { std::ffi::CStr::from_ptr(passwd.pw_name).to_string_lossy().into_owned() };
-
-        users.push(User { name, home });
+        if let Some(user) = user_from_passwd(&passwd) {
+            users.push(user);
+        }
     }
 
     // safe because i dont know what might be unsafe about it
@@ -93,33 +211,671 @@ fn list_users() -> Vec<User> {
     return users;
 }
 
+/// names of the entries of a directory
+fn dir_names(dir: &str) -> Vec<String> {
+    match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok()).map(|entry| entry.file_name().to_string_lossy().into_owned()).collect(),
+        Err(_) => vec![],
+    }
+}
+
+/// All users, and the names of those that getpwent did not return. getpwent misses directory
+/// users if NSS does not enumerate them (SSSD defaults to `enumerate = false`), so we also look
+/// up home directories, logged in users and files in /etc/ssh/authorized_keys.d by name.
+fn discover_users(settings: &diskstate::Settings) -> (Vec<User>, Vec<String>) {
+    let mut users = list_users();
+    let mut candidates: Vec<String> = settings.home_roots.iter().flat_map(|root| dir_names(root)).collect();
+    candidates.extend(users::logged_in());
+    candidates.extend(dir_names("/etc/ssh/authorized_keys.d"));
+
+    let mut unenumerated = vec![];
+    for name in candidates {
+        if users.iter().any(|u| u.name == name) {
+            continue;
+        }
+        if let Some(user) = get_user(&name) {
+            unenumerated.push(user.name.clone());
+            users.push(user);
+        }
+    }
+    (users, unenumerated)
+}
+
+/// All users that a hog has to consider, including those that NSS does not enumerate
+pub fn all_users(settings: &diskstate::Settings) -> Vec<User> {
+    discover_users(settings).0
+}
+
+/// Members of a unix group, including the users that have it as their primary group. None if the
+/// group does not exist.
+pub fn group_members(group: &str) -> Option<Vec<String>> {
+    let name = std::ffi::CString::new(group).ok()?;
+    let group = unsafe { libc::getgrnam(name.as_ptr()) };
+    if group.is_null() {
+        return None;
+    }
+    // safe because we null check before accessing it
+    let group = unsafe { &*group };
+    let gid = group.gr_gid;
+    let mut members = vec![];
+    let mut member = group.gr_mem;
+    // safe because gr_mem is a null terminated array
+    while !member.is_null() && !unsafe { *member }.is_null() {
+        members.push(unsafe { std::ffi::CStr::from_ptr(*member) }.to_string_lossy().into_owned());
+        member = unsafe { member.add(1) };
+    }
+    for user in list_users() {
+        if user.gid == gid && !members.contains(&user.name) {
+            members.push(user.name);
+        }
+    }
+    Some(members)
+}
+
+/// whether the current hog locks this user out
+pub fn locked_out(state: &diskstate::DiskState, user: &str) -> bool {
+    get_user(user).is_some_and(|user| locks_out_user(state, &user))
+}
+
+/// whether the current hog locks this user out. Only the ssh module locks users out, other hogs
+/// (e.g. `hog --throttle` or after `release --only ssh`) let everyone in.
+fn locks_out_user(state: &diskstate::DiskState, user: &User) -> bool {
+    state.hogger.is_some()
+        && state.active_modules.contains(&Module::Ssh)
+        && !state.allowed_users.contains(&user.name)
+        && state.settings.locks_out(user)
+}
+
+/// What the ssh module has to restrict to lock out a set of users
+struct SshTargets {
+    auth_key_files: Vec<String>,
+    principals_files: Vec<String>,
+    /// AuthorizedKeysCommand (false) and AuthorizedPrincipalsCommand (true) templates
+    commands: Vec<(Vec<String>, bool)>,
+    /// users that can still log in with certificates
+    unrestricted_ca: Vec<String>,
+    /// files that are not restricted because users that are not locked out log in with them as well
+    shared: Vec<(String, Vec<String>)>,
+    /// links to files that we do not follow, with the reason
+    refused: Vec<(String, String)>,
+    /// the locked out users and the files they log in with
+    users: Vec<(User, Vec<String>)>,
+}
+
+/// Where links of users may point to files of root: the nix store is world-readable and
+/// read-only, so we leak and change nothing by restricting a copy of such a file
+const TRUSTED_LINK_TARGETS: &[&str] = &["/nix/store/"];
+
+/// Whether root controls every link that `file` resolves through: the links belong to root, and
+/// so do the directories that lead to them, which only root may write
+fn links_root_controlled(file: &str) -> bool {
+    use std::path::{Component, Path, PathBuf};
+    let only_root_writes = |dir: &Path| fs::symlink_metadata(dir).is_ok_and(|meta| meta.uid() == 0 && meta.mode() & 0o022 == 0);
+    let push = |rest: &mut Vec<PathBuf>, path: &Path| {
+        for component in path.components().rev() {
+            match component {
+                Component::Normal(name) => rest.push(PathBuf::from(name)),
+                Component::ParentDir => rest.push(PathBuf::from("..")),
+                Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+            }
+        }
+    };
+    let mut current = PathBuf::from("/");
+    let mut controlled = only_root_writes(&current);
+    // components that are left to resolve, the next one last
+    let mut rest = vec![];
+    push(&mut rest, Path::new(file));
+    let mut links = 0;
+    while let Some(name) = rest.pop() {
+        if name == Path::new("..") {
+            current.pop();
+            continue;
+        }
+        let next = current.join(&name);
+        let metadata = match fs::symlink_metadata(&next) {
+            Ok(metadata) => metadata,
+            Err(_) => return false,
+        };
+        if !metadata.file_type().is_symlink() {
+            current = next;
+            controlled = controlled && (rest.is_empty() || only_root_writes(&current));
+            continue;
+        }
+        // the limit of the kernel for nested links
+        links += 1;
+        if !controlled || metadata.uid() != 0 || links > 40 {
+            return false;
+        }
+        let target = match fs::read_link(&next) {
+            Ok(target) => target,
+            Err(_) => return false,
+        };
+        if target.is_absolute() {
+            current = PathBuf::from("/");
+            controlled = only_root_writes(&current);
+        }
+        push(&mut rest, &target);
+    }
+    true
+}
+
+/// Resolve symlinks (e.g. into /etc/static or the nix store), so that we restrict and recognize
+/// the file that sshd actually reads. Files that do not exist are returned as they are. We read
+/// the target as root and mount over it, so we only follow links that root controls (e.g. set up
+/// by config management), and links of `owner` to a file of `owner` or to a world-readable file
+/// of root in a trusted location.
+fn resolve(file: &str, owner: &User, mounts: &MountTable) -> Result<String, String> {
+    let resolved = match fs::canonicalize(file) {
+        Ok(resolved) => resolved,
+        Err(_) => return Ok(file.to_string()),
+    };
+    let resolved_file = resolved.to_string_lossy().into_owned();
+    if resolved_file == file {
+        return Ok(resolved_file);
+    }
+    let metadata = fs::metadata(&resolved).map_err(|e| format!("links to {}: {}", resolved_file, e))?;
+    let trusted = metadata.uid() == 0
+        && metadata.mode() & 0o004 != 0
+        && TRUSTED_LINK_TARGETS.iter().any(|dir| resolved_file.starts_with(dir));
+    // our overlay belongs to root, but we checked the file when we mounted over it
+    match metadata.is_file() && (metadata.uid() == owner.uid || trusted || is_ours(mounts, &resolved_file) || links_root_controlled(file)) {
+        true => Ok(resolved_file),
+        false => Err(format!("links to {} (owned by uid {}), which is not a file of {}, and root does not control the link. Not following it.", resolved_file, metadata.uid(), owner.name)),
+    }
+}
+
+/// The key and principals files that a user can log in with, symlinks resolved (refused links
+/// are returned as they are)
+fn login_files(user: &User, settings: &diskstate::Settings, sshd: &sshd_config::SshdConfig, mounts: &MountTable) -> Vec<String> {
+    let mut patterns = settings.authorized_keys_file.clone();
+    patterns.extend(sshd.authorized_keys_files(user));
+    patterns.extend(sshd.paths(user, "authorizedprincipalsfile"));
+    patterns
+        .iter()
+        .filter_map(|pattern| diskstate::expand_authorized_keys_file_for(pattern, user).ok().flatten())
+        .map(|file| resolve(&file, user, mounts).unwrap_or(file))
+        .collect()
+}
+
+/// Resolve and de-duplicate the files of users. Files that users in `shared_with` log in with as
+/// well are moved to `shared`: restricting them would lock those users out too. Links that
+/// `resolve` does not follow are moved to `refused`.
+fn resolve_targets(
+    files: Vec<(&User, String)>,
+    shared_with: &[(String, Vec<String>)],
+    shared: &mut Vec<(String, Vec<String>)>,
+    refused: &mut Vec<(String, String)>,
+    mounts: &MountTable,
+) -> Vec<String> {
+    let mut resolved: Vec<String> = vec![];
+    for (owner, file) in files {
+        let file = match resolve(&file, owner, mounts) {
+            Ok(file) => file,
+            Err(err) => {
+                if !refused.iter().any(|(f, _)| *f == file) {
+                    refused.push((file, err));
+                }
+                continue;
+            }
+        };
+        let users: Vec<String> = shared_with.iter().filter(|(_, files)| files.contains(&file)).map(|(user, _)| user.clone()).collect();
+        if !users.is_empty() {
+            if !shared.iter().any(|(f, _)| *f == file) {
+                shared.push((file, users));
+            }
+        } else if !resolved.contains(&file) {
+            resolved.push(file);
+        }
+    }
+    resolved
+}
+
+/// The users that a hog locks out, and the files that everyone else logs in with. These are the
+/// allowed users, but also those that the policy leaves alone (e.g. service accounts or
+/// lockout_exclude). Their files must stay usable.
+fn split_users(
+    users: Vec<User>,
+    exclude_users: &[String],
+    settings: &diskstate::Settings,
+    files: impl Fn(&User) -> Vec<String>,
+) -> (Vec<User>, Vec<(String, Vec<String>)>) {
+    let (users, others): (Vec<User>, Vec<User>) = users
+        .into_iter()
+        .partition(|u| !exclude_users.contains(&u.name) && settings.locks_out(u));
+    let shared_with = others.iter().map(|u| (u.name.clone(), files(u))).collect();
+    (users, shared_with)
+}
+
+fn ssh_targets(exclude_users: &[String], settings: &diskstate::Settings, mounts: &MountTable) -> SshTargets {
+    let sshd = sshd_config::SshdConfig::load();
+    let (users, shared_with) = split_users(all_users(settings), exclude_users, settings, |u| login_files(u, settings, &sshd, mounts));
+    let mut shared = vec![];
+    let mut refused = vec![];
+    let auth_key_files = resolve_targets(diskstate::expand_authorized_keys_file(settings, &sshd, &users), &shared_with, &mut shared, &mut refused, mounts);
+
+    // certificates: principals files and commands
+    let mut principals_files = vec![];
+    let mut commands: Vec<(Vec<String>, bool)> = vec![];
+    let mut unrestricted_ca = vec![];
+    for user in &users {
+        for pattern in sshd.paths(user, "authorizedprincipalsfile") {
+            match diskstate::expand_authorized_keys_file_for(&pattern, user) {
+                Ok(Some(file)) if !principals_files.contains(&(user, file.clone())) => principals_files.push((user, file)),
+                Ok(_) => {},
+                Err(e) => eprintln!("WARN: skipping AuthorizedPrincipalsFile of {}: {}", user.name, e),
+            }
+        }
+        for (keyword, principals) in [("authorizedkeyscommand", false), ("authorizedprincipalscommand", true)] {
+            for template in sshd.values(user, keyword) {
+                if template.first().is_some_and(|b| b != "none") && !commands.contains(&(template.clone(), principals)) {
+                    commands.push((template, principals));
+                }
+            }
+        }
+        // without principals file or command, sshd accepts certificates that list the user name
+        if !sshd.paths(user, "trustedusercakeys").is_empty()
+            && sshd.paths(user, "authorizedprincipalsfile").is_empty()
+            && sshd.paths(user, "authorizedprincipalscommand").is_empty() {
+            unrestricted_ca.push(user.name.clone());
+        }
+    }
+    let principals_files = resolve_targets(principals_files, &shared_with, &mut shared, &mut refused, mounts);
+    let users = users.into_iter().map(|user| {
+        let files = login_files(&user, settings, &sshd, mounts);
+        (user, files)
+    }).collect();
+    SshTargets { auth_key_files, principals_files, commands, unrestricted_ca, shared, refused, users }
+}
+
 fn hog_ssh(exclude_users: Vec<String>, state: &mut diskstate::DiskState) {
-    let users = list_users().into_iter().filter(|u| !exclude_users.contains(&u.name)).collect::<Vec<User>>();
-    let all_auth_key_files: Vec<String> = diskstate::expand_authorized_keys_file(&state.settings, users);
-    let all_files_len = all_auth_key_files.len();
-    let auth_key_files: Vec<String> = 
-        all_auth_key_files.into_iter()
-        // filter out files that we recorded as overmounted
-        .filter(|f| !state.overmounts.contains(f))
-        // filter out files that have an unknown overmount
-        .filter(|f| !is_overmounted(f)).collect();
-    let mut failed = 0;
-    for file in &auth_key_files {
-        match overmount(&file) {
-            Ok(_) => { state.overmounts.push(file.clone()); },
-            Err(None) => { }, // ignore files that dont exist
-            Err(Some(_errno)) => { failed += 1; },
+    let mounts = MountTable::load();
+    let SshTargets { auth_key_files, principals_files, commands, unrestricted_ca, shared, refused, users } = ssh_targets(&exclude_users, &state.settings, &mounts);
+    let (command, pty) = ssh_hogged_command(&state.settings);
+    let mut auth_paths = vec![];
+
+    let (overmounted, skipped, mut failed) = overmount_all(auth_key_files, &users, &mounts, false, |keys| authorized_keys::restrict(keys, &command, pty), state);
+    println!("{} users locked out of ssh ({} skipped, {} failed)", overmounted, skipped, failed.len());
+    auth_paths.push(diskstate::AuthPath {
+        name: String::from("AuthorizedKeysFile"),
+        covered: failed.is_empty(),
+        note: format!("{} files restricted, {} failed", overmounted + skipped, failed.len()),
+    });
+
+    if !principals_files.is_empty() {
+        let (overmounted, skipped, failed_principals) = overmount_all(principals_files, &users, &mounts, true, |principals| authorized_keys::restrict_principals(principals, &command, pty), state);
+        println!("{} principals files restricted ({} skipped, {} failed)", overmounted, skipped, failed_principals.len());
+        auth_paths.push(diskstate::AuthPath {
+            name: String::from("AuthorizedPrincipalsFile"),
+            covered: failed_principals.is_empty(),
+            note: format!("{} files restricted, {} failed", overmounted + skipped, failed_principals.len()),
+        });
+        failed.extend(failed_principals);
+    }
+    for (file, err) in &refused {
+        println!("WARN: not restricting {}: {}", file, err);
+        auth_paths.push(diskstate::AuthPath { name: file.clone(), covered: false, note: err.clone() });
+    }
+    failed.extend(refused);
+    state.user_coverage = user_coverage(&users, &failed, &state.settings);
+    for (template, principals) in commands {
+        let name = match principals {
+            true => format!("AuthorizedPrincipalsCommand {}", template[0]),
+            false => format!("AuthorizedKeysCommand {}", template[0]),
+        };
+        let (covered, note) = match keys_command::wrap(state, &template, principals) {
+            Ok(_) => (true, String::from("wrapped")),
+            Err(e) => (false, e),
+        };
+        println!("{}: {}", name, note);
+        auth_paths.push(diskstate::AuthPath { name, covered, note });
+    }
+    if !unrestricted_ca.is_empty() {
+        println!("WARN: {} users can still log in with certificates (TrustedUserCAKeys without AuthorizedPrincipalsFile)", unrestricted_ca.len());
+        auth_paths.push(diskstate::AuthPath {
+            name: String::from("TrustedUserCAKeys"),
+            covered: false,
+            note: format!("certificates naming these users are accepted: {}", unrestricted_ca.join(", ")),
+        });
+    }
+    for (file, users) in shared {
+        println!("WARN: not restricting {}, users that are not locked out log in with it too: {}", file, users.join(", "));
+        auth_paths.push(diskstate::AuthPath {
+            name: file,
+            covered: false,
+            note: format!("shared with users that are not locked out: {} (only pam-check locks the others out)", users.join(", ")),
+        });
+    }
+    state.auth_paths = auth_paths;
+}
+
+/// The locked out users with files that could not be restricted. pam-check still denies them,
+/// if it is installed for sshd and the gate is hard.
+fn user_coverage(users: &[(User, Vec<String>)], failed: &[(String, String)], settings: &diskstate::Settings) -> Vec<diskstate::AuthPath> {
+    let pam = pam::hook_installed("sshd") && settings.gate == diskstate::Gate::Hard;
+    let mut coverage = vec![];
+    for (user, files) in users {
+        let problems: Vec<String> = failed.iter().filter(|(file, _)| files.contains(file)).map(|(file, err)| format!("{}: {}", file, err)).collect();
+        if problems.is_empty() {
+            continue;
+        }
+        let note = match pam {
+            true => format!("denied by pam-check, keys not restricted ({})", problems.join("; ")),
+            false => format!("keys not restricted ({})", problems.join("; ")),
+        };
+        if !pam {
+            println!("WARN: {} is NOT locked out: {}", user.name, note);
+        }
+        coverage.push(diskstate::AuthPath { name: user.name.clone(), covered: pam, note });
+    }
+    coverage
+}
+
+/// The key and principals files of the users that the hog locks out, whether they exist or not
+pub fn key_files(state: &diskstate::DiskState) -> Vec<String> {
+    let targets = ssh_targets(&state.allowed_users, &state.settings, &MountTable::load());
+    targets.auth_key_files.into_iter().chain(targets.principals_files).collect()
+}
+
+/// Overlays that were written to while hogged (e.g. a key was added), with their content and
+/// their content restricted again
+fn edited_overlays(state: &diskstate::DiskState, targets: &SshTargets) -> Vec<(String, String, String)> {
+    let (command, pty) = ssh_hogged_command(&state.settings);
+    state.overmounts.iter().filter_map(|file| {
+        let overlay = fs::read_to_string(overlay_file(file)).ok()?;
+        let restricted = match (targets.auth_key_files.contains(file), targets.principals_files.contains(file)) {
+            (true, _) => authorized_keys::restrict(&overlay, &command, pty),
+            (_, true) => authorized_keys::restrict_principals(&overlay, &command, pty),
+            // wrapped commands and files of users that are allowed by now
+            _ => return None,
+        };
+        (restricted != overlay).then(|| (file.clone(), overlay, restricted))
+    }).collect()
+}
+
+/// What changed since the hog and needs a rescan: new key files and edited overlays
+pub fn ssh_changes(state: &diskstate::DiskState) -> Vec<String> {
+    let mounts = MountTable::load();
+    let targets = ssh_targets(&state.allowed_users, &state.settings, &mounts);
+    let files = targets.auth_key_files.iter().chain(targets.principals_files.iter());
+    let mut changes: Vec<String> = files
+        .filter(|file| may_exist(file) && !state.overmounts.contains(file) && !mounts.is_mounted(file))
+        .map(|file| format!("new key file {}", file))
+        .collect();
+    changes.extend(edited_overlays(state, &targets).into_iter().map(|(file, _, _)| format!("{} was edited", file)));
+    changes
+}
+
+/// Lock out key files and users that appeared since the hog, and keys that were added to files
+/// that are locked out already
+pub fn rescan_ssh(state: &mut diskstate::DiskState) {
+    let targets = ssh_targets(&state.allowed_users, &state.settings, &MountTable::load());
+    for (file, overlay, restricted) in edited_overlays(state, &targets) {
+        // the release merges the keys as they were added, not our restricted version of them
+        let added: Vec<&str> = overlay.lines().zip(restricted.lines()).filter(|(line, r)| line != r).map(|(line, _)| line).collect();
+        if let Err(err) = save_added_lines(&file, &added) {
+            println!("WARN: could not save the keys that were added to {}, the release will not keep them: {}", file, err);
+        }
+        // write in place: the bind mount refers to this inode
+        match fs::write(overlay_file(&file), restricted) {
+            Ok(_) => println!("restricted keys that were added to {} while hogged", file),
+            Err(err) => println!("WARN: could not restrict keys that were added to {}: {}", file, err),
+        }
+    }
+    hog_ssh(state.allowed_users.clone(), state);
+}
+
+/// The modules a hog command selects, in the order in which they are applied
+fn select_modules(cmd: &HogCommand) -> Vec<Module> {
+    let mut modules = match &cmd.only {
+        Some(only) => only.clone(),
+        None => DEFAULT_MODULES.to_vec(),
+    };
+    if cmd.freeze {
+        modules.push(Module::Freeze);
+    }
+    if cmd.throttle.throttle {
+        modules.push(Module::Throttle);
+        // soft hog: throttle others instead of locking them out
+        if cmd.only.is_none() {
+            modules.retain(|m| *m != Module::Ssh);
+        }
+    }
+    modules.retain(|m| !cmd.except.contains(m));
+    // built-in modules in their order, then external modules in the given order
+    let external = modules.iter().filter(|m| matches!(m, Module::External(_))).cloned();
+    let mut ordered: Vec<Module> = ALL_MODULES.into_iter().filter(|m| modules.contains(m)).collect();
+    for module in external {
+        if !ordered.contains(&module) {
+            ordered.push(module);
+        }
+    }
+    ordered
+}
+
+/// Locks other users out of ssh by overmounting their keys, or of everything via /run/nologin
+/// (see the backend setting)
+pub struct SshKeys;
+
+impl SshKeys {
+    /// lock out everyone who is not allowed and not locked out yet
+    fn apply_ssh(&self, state: &mut diskstate::DiskState) {
+        match state.settings.backend {
+            diskstate::Backend::Overmount => {
+                if state.settings.gate == diskstate::Gate::Soft {
+                    gate::prepare();
+                }
+                hog_ssh(state.allowed_users.clone(), state);
+                watch::start();
+            }
+            diskstate::Backend::Nologin => nologin::hog(state),
+        }
+    }
+}
+
+impl HogModule for SshKeys {
+    fn apply(&self, state: &mut diskstate::DiskState, _cmd: &HogCommand) {
+        self.apply_ssh(state);
+    }
+
+    fn revert(&self, state: &mut diskstate::DiskState) {
+        watch::stop();
+        release_ssh(state);
+        nologin::release(state);
+        gate::release(state);
+        state.auth_paths.clear();
+        state.user_coverage.clear();
+    }
+
+    fn allow(&self, state: &mut diskstate::DiskState, _users: &[String]) {
+        let allowed = state.allowed_users.clone();
+        state.user_coverage.retain(|user| !allowed.contains(&user.name));
+        if state.settings.backend == diskstate::Backend::Nologin {
+            // rewrites the allowed users
+            return nologin::hog(state);
+        }
+        // release the files that no locked out user needs anymore. Wrapped keys commands stay,
+        // they check the allowed users on every login.
+        let mounts = MountTable::load();
+        let targets = ssh_targets(&state.allowed_users, &state.settings, &mounts);
+        for file in state.overmounts.clone() {
+            if is_wrapped_command(state, &file) || targets.auth_key_files.contains(&file) || targets.principals_files.contains(&file) {
+                continue;
+            }
+            if let Err(err) = unmount(&mounts, &file) {
+                println!("failed to release {}: {:?}", file, err);
+                continue;
+            }
+            released(state, &file);
+            let _ = fs::remove_file(overlay_file(&file));
+            diskstate::store(state);
+        }
+    }
+
+    fn deny(&self, state: &mut diskstate::DiskState, _users: &[String]) {
+        self.apply_ssh(state);
+    }
+
+    fn status(&self, state: &diskstate::DiskState) -> Vec<String> {
+        let mut lines = vec![];
+        if state.nologin {
+            lines.push(format!("Logins are disabled via /run/nologin for everyone except root and {}", state.allowed_users.join(", ")));
+        }
+        if !state.overmounts.is_empty() || !state.auth_paths.is_empty() {
+            let mounts = MountTable::load();
+            let active_overmounts = state.overmounts.iter().filter(|file| is_ours(&mounts, file)).count();
+            lines.push(format!("{} keys were disabled to hog, {} keys are still disabled", state.overmounts.len(), active_overmounts));
+            match namespace::overmounts_seen_by_sshd(&state.overmounts) {
+                Some(_) if state.overmounts.is_empty() => {},
+                Some(view) if view.seen < state.overmounts.len() => lines.push(format!(
+                    "WARN: sshd (pid {}) sees only {} of them{}",
+                    view.pid,
+                    view.seen,
+                    match (view.other_namespace, view.private) {
+                        (true, 0) => String::from(", it runs in another mount namespace"),
+                        (true, private) => format!(", it runs in another mount namespace and {} of our mounts do not propagate", private),
+                        (false, _) => String::new(),
+                    }
+                )),
+                Some(view) => lines.push(format!("sshd (pid {}) sees all of them", view.pid)),
+                None => lines.push(String::from("sshd is not running, can not check which keys it sees")),
+            }
+            for path in &state.auth_paths {
+                let covered = if path.covered { "blocked" } else { "NOT blocked" };
+                lines.push(format!("  {:<12} {}: {}", covered, path.name, path.note));
+            }
+            if !state.user_coverage.is_empty() {
+                lines.push(String::from("Users whose keys could not be restricted:"));
+                for user in &state.user_coverage {
+                    let covered = if user.covered { "blocked" } else { "NOT blocked" };
+                    lines.push(format!("  {:<12} {}: {}", covered, user.name, user.note));
+                }
+            }
+            lines.extend(pam::status(&state.settings));
+        }
+        if state.settings.gate == diskstate::Gate::Soft && state.settings.backend == diskstate::Backend::Overmount {
+            lines.push(String::from("Soft gate: locked out users can log in with a key after typing a confirmation"));
+            for entry in state.overrides.iter().cloned().chain(gate::pending()) {
+                lines.push(format!("  {} overrode the hog at {}", entry.user, entry.time.format("%Y-%m-%d %H:%M")));
+            }
         }
+        lines
     }
-    // println!("overmounts: {:?}", state.overmounts);
-    println!("{} users locked out of ssh ({} skipped, {} failed)", auth_key_files.len(), all_files_len - auth_key_files.len(), failed);
 
-    // let mut command = vec![String::from("pkill"), String::from("-u")];
-    // command.extend(users);
-    // run(&command);
+    fn leftovers(&self, state: &diskstate::DiskState) -> Vec<String> {
+        let mut leftovers: Vec<String> = state.overmounts.iter().map(|file| format!("overmounted: {}", file)).collect();
+        if state.nologin {
+            leftovers.push(String::from("logins disabled: /run/nologin"));
+        }
+        leftovers
+    }
+
+    fn dry_run(&self, state: &diskstate::DiskState, _cmd: &HogCommand) -> Vec<String> {
+        if state.settings.backend == diskstate::Backend::Nologin {
+            return vec![format!("write /run/nologin, allowing root and {}", state.allowed_users.join(", "))];
+        }
+        let mounts = MountTable::load();
+        let targets = ssh_targets(&state.allowed_users, &state.settings, &mounts);
+        let files = targets.auth_key_files.iter().chain(targets.principals_files.iter());
+        let mut lines: Vec<String> = files
+            .filter(|file| may_exist(file))
+            .map(|file| match (state.overmounts.contains(file), mounts.at(file).last()) {
+                (true, _) => format!("skip {} (already restricted)", file),
+                (false, Some(other)) => format!("skip {} (mounted over by someone else: {})", file, other.describe()),
+                (false, None) => format!("restrict {}", file),
+            })
+            .collect();
+        for (template, _principals) in &targets.commands {
+            lines.push(format!("wrap {}", template[0]));
+        }
+        if !targets.unrestricted_ca.is_empty() {
+            lines.push(format!("NOT blocked: certificates for {}", targets.unrestricted_ca.join(", ")));
+        }
+        for (file, users) in &targets.shared {
+            lines.push(format!("NOT blocked: {} (shared with users that are not locked out: {})", file, users.join(", ")));
+        }
+        for (file, err) in &targets.refused {
+            lines.push(format!("NOT blocked: {} ({})", file, err));
+        }
+        lines
+    }
 }
 
-pub fn do_hog(mut users: Vec<String>, state: &mut diskstate::DiskState) {
+/// Only the hogger may change the allowed users of a hog
+fn check_hogger(state: &diskstate::DiskState) {
+    let me = users::my_username().unwrap();
+    match &state.hogger {
+        None => panic!("The host is not hogged."),
+        Some(hogger) if hogger.user != me => panic!("Only {} can change who is allowed on this hog.", hogger.user),
+        Some(_) => {},
+    }
+}
+
+/// Let a user or the members of a group in while the hog stays up
+pub fn do_allow(name: &str, state: &mut diskstate::DiskState) {
+    check_hogger(state);
+    let users = match (get_user(name), group_members(name)) {
+        (Some(_), _) => vec![name.to_string()],
+        (None, Some(members)) => members,
+        (None, None) => panic!("There is no user or group {}", name),
+    };
+    let users: Vec<String> = users.into_iter().filter(|u| !state.allowed_users.contains(u)).collect();
+    if users.is_empty() {
+        println!("{} is already allowed", name);
+        return;
+    }
+    diskstate::begin(state, diskstate::Operation::Allow);
+    let previous = state.allowed_users.clone();
+    state.allowed_users.extend(users.iter().cloned());
+    diskstate::journal(state, diskstate::Step::AllowedUsers(previous));
+    for module in state.active_modules.clone() {
+        modules::get(&module).allow(state, &users);
+    }
+    diskstate::log(state, format!("allow: {}", users.join(", ")));
+    diskstate::commit(state);
+    println!("allowed: {}", users.join(", "));
+}
+
+/// Lock a user out again while the hog stays up
+pub fn do_deny(user: &str, state: &mut diskstate::DiskState) {
+    check_hogger(state);
+    if get_user(user).is_none() {
+        panic!("There is no user {}", user);
+    }
+    if state.settings.exempt_users.iter().any(|u| u == user) {
+        panic!("{} is in exempt_users and can not be locked out.", user);
+    }
+    if !state.allowed_users.iter().any(|u| u == user) {
+        println!("{} is not allowed anyway", user);
+        return;
+    }
+    if users::my_username().is_some_and(|me| me == user) {
+        eprintln!("WARNING: You ({}) are locking yourself out of new logins to this host.", user);
+    }
+    let users = vec![user.to_string()];
+    diskstate::begin(state, diskstate::Operation::Deny);
+    let previous = state.allowed_users.clone();
+    state.allowed_users.retain(|u| u != user);
+    diskstate::journal(state, diskstate::Step::AllowedUsers(previous));
+    for module in state.active_modules.clone() {
+        modules::get(&module).deny(state, &users);
+    }
+    diskstate::log(state, format!("deny: {}", user));
+    diskstate::commit(state);
+    println!("denied: {}", user);
+}
+
+/// Undo a single module. Also cleans up when the module is not recorded as active, to converge
+/// towards the intended state.
+fn release_module(state: &mut diskstate::DiskState, module: &Module) {
+    modules::get(module).revert(state);
+    state.active_modules.retain(|m| m != module);
+}
+
+/// Check whether `cmd` may hog. Returns the modules to apply, the allowed users and the claim
+/// that hogs.
+pub fn prepare_hog(cmd: &HogCommand, state: &diskstate::DiskState) -> (Vec<Module>, Vec<String>, diskstate::Claim) {
+    let modules = select_modules(cmd);
+    let mut users = cmd.users.clone();
     let me = users::my_username().unwrap();
     let claim = match state.claims.iter().find(|claim| claim.user == me && claim.exclusive) {
         Some(claim) => claim.clone(),
@@ -132,68 +888,547 @@ pub fn do_hog(mut users: Vec<String>, state: &mut diskstate::DiskState) {
             panic!("Hogging not allowed. The system is already hogged other user {}.", hogger.user);
         }
     }
-
-    println!("hog users:");
-    if users.len() == 0 {
+    if modules.is_empty() {
+        panic!("Nothing to hog: no modules selected.");
+    }
+    for group in &cmd.groups {
+        let members = match group_members(group) {
+            Some(members) => members,
+            None => panic!("Unknown group {}", group),
+        };
+        for member in members {
+            if !users.contains(&member) {
+                users.push(member);
+            }
+        }
+    }
+    if cmd.users.is_empty() && cmd.groups.is_empty() {
         users.push(String::from("root"));
-        users.push(me);
+        users.push(me.clone());
+    }
+    for exempt in &state.settings.exempt_users {
+        if !users.contains(exempt) {
+            users.push(exempt.clone());
+        }
+    }
+    // the active modules lock out the allowed users of the hog. Changing them needs allow/deny.
+    if state.hogger.is_some() && !state.active_modules.is_empty() {
+        if cmd.users.is_empty() && cmd.groups.is_empty() {
+            users = state.allowed_users.clone();
+        } else if users.iter().any(|u| !state.allowed_users.contains(u)) || state.allowed_users.iter().any(|u| !users.contains(u)) {
+            panic!(
+                "The host is already hogged with the allowed users {}. Use `{} allow <user>` or `{} deny <user>` to change them.",
+                state.allowed_users.join(", "), util::prog_name(), util::prog_name()
+            );
+        }
+    }
+    let unenumerated = discover_users(&state.settings).1;
+    if modules.contains(&Module::Ssh) && !unenumerated.is_empty() {
+        eprintln!("WARN: NSS does not list all users (e.g. SSSD with enumerate = false). Found {} more by their home directory, session or keys: {}", unenumerated.len(), unenumerated.join(", "));
+        eprintln!("WARN: Users that have none of these are not found and not locked out.");
+    }
+    let locks_out_me = modules.contains(&Module::Ssh) && get_user(&me).is_some_and(|u| state.settings.locks_out(&u));
+    if !users.contains(&me) && locks_out_me {
+        eprintln!("WARNING: **********************************************************************");
+        eprintln!("WARNING: You ({}) are not in the allowed users: {}", me, users.join(", "));
+        eprintln!("WARNING: This hog locks you out of new logins to this host.");
+        eprintln!("WARNING: Add yourself with `-u {}` unless this is intended.", me);
+        eprintln!("WARNING: **********************************************************************");
     }
+    (modules, users, claim)
+}
+
+pub fn do_hog(cmd: HogCommand, state: &mut diskstate::DiskState, lock: &mut Option<diskstate::StateLock>) {
+    let (modules, users, claim) = prepare_hog(&cmd, state);
+
+    println!("hog users:");
     users.as_slice().into_iter().for_each(|i| print!("{} ", i));
     println!("");
-    hog_ssh(users, state);
-    state.hogger = Some(claim);
+    println!("hog modules: {}", modules.iter().map(|m| m.to_string()).collect::<Vec<String>>().join(", "));
+    if modules.contains(&Module::Throttle) && !modules.contains(&Module::Ssh) {
+        println!("soft hog: other users are throttled instead of locked out");
+    }
+    diskstate::begin(state, diskstate::Operation::Hog);
+    let previous = std::mem::replace(&mut state.allowed_users, users.clone());
+    diskstate::journal(state, diskstate::Step::AllowedUsers(previous));
+    let previous = state.hogger.replace(claim);
+    diskstate::journal(state, diskstate::Step::Hogger(previous));
+
+    for module in modules {
+        if state.active_modules.contains(&module) {
+            continue;
+        }
+        // journal before applying, so that a rollback also reverts a module that failed halfway
+        state.active_modules.push(module.clone());
+        diskstate::journal(state, diskstate::Step::Module(module.clone()));
+        modules::get(&module).apply(state, &cmd);
+    }
+    diskstate::commit(state);
+
+    // not part of the transaction: terminated sessions can not be rolled back
+    if let Some(grace) = cmd.evict {
+        evict::do_evict(state, grace, lock);
+    }
+}
+
+/// unmount the overlay of a single file (if it is still mounted). Leaves it alone if someone
+/// mounted something over it: unmounting would remove theirs. Fails if the overlay is mounted
+/// somewhere else than at `file`, so that we do not forget about it.
+fn unmount(mounts: &MountTable, file: &str) -> Result<(), nix::errno::Errno> {
+    let stack = mounts.at(file);
+    let ours = match stack.iter().find(|mount| is_our_mount(mount, file)) {
+        Some(ours) => ours,
+        // e.g. `file` was a link, and the mount landed on its target
+        None if !file.starts_with(OVERLAY_PATH.as_str()) => match mounts.iter().find(|mount| is_our_mount(mount, file)) {
+            Some(elsewhere) => {
+                println!("WARN: our overlay of {} is mounted at {} instead. Unmount it with `umount {}`.", file, elsewhere.target, elsewhere.target);
+                return Err(nix::errno::Errno::ENOENT);
+            }
+            // already unmounted, e.g. by a reboot
+            None => return Ok(()),
+        },
+        None => return Ok(()),
+    };
+    if let Some(other) = stack.iter().find(|mount| mount.parent == ours.id) {
+        println!("WARN: {} is mounted over our overlay of {}", other.describe(), file);
+        return Err(nix::errno::Errno::EBUSY);
+    }
+    nix::mount::umount(std::path::Path::new(file))?;
+    println!("released {}", file);
+    Ok(())
+}
 
-    // run other modeules
-    systemd_units::disable_resource(state);
+/// Where rescan_ssh keeps the lines that were added to the overlay of `file`, before it restricted
+/// them
+fn added_lines_file(file: &str) -> String {
+    format!("{}/{}.added", EDITS_PATH.as_str(), escape(file))
+}
 
-    // let mut command = vec![String::from("pkill"), String::from("-u")];
-    // command.extend(users);
-    // run(&command);
+fn save_added_lines(file: &str, lines: &[&str]) -> std::io::Result<()> {
+    fs::create_dir_all(EDITS_PATH.as_str())?;
+    let mut saved = fs::OpenOptions::new().create(true).append(true).mode(0o600).open(added_lines_file(file))?;
+    saved.write_all(lines.iter().map(|line| format!("{}\n", line)).collect::<String>().as_bytes())
+}
+
+/// What to keep of an overlay that was edited while hogged: the lines to append to the original
+/// file as they were written, and how many of our lines were removed or changed. `saved_lines` are
+/// the lines that rescan_ssh restricted after they were added. None if the overlay is as we wrote
+/// it.
+fn merge_edits(original: &str, overlay: &str, saved_lines: &str, principals: bool, command: &str, pty: bool) -> Option<(Vec<String>, usize)> {
+    let restrict = |text: &str| match principals {
+        false => authorized_keys::restrict(text, command, pty),
+        true => authorized_keys::restrict_principals(text, command, pty),
+    };
+    let ours = restrict(original);
+    if ours == overlay {
+        return None;
+    }
+
+    let new_lines: Vec<&str> = overlay.lines().filter(|line| !line.trim().is_empty() && !ours.lines().any(|l| l == *line)).collect();
+    // lines that the rescan restricted, if they are still there
+    let mut added: Vec<&str> = saved_lines.lines().filter(|line| new_lines.contains(&restrict(line).as_str())).collect();
+    let restricted_added: Vec<String> = added.iter().map(|line| restrict(line)).collect();
+    // lines that were added since the last rescan
+    added.extend(new_lines.iter().filter(|line| authorized_keys::unrestrict(line, command, pty) == **line));
+    added.retain(|line| !original.lines().any(|l| l == *line));
+    added.dedup();
+    // restricted lines that we do not know the original of
+    let unknown = new_lines.iter().filter(|line| authorized_keys::unrestrict(line, command, pty) != **line && !restricted_added.contains(&line.to_string())).count();
+    let removed = ours.lines().filter(|line| !line.trim().is_empty() && !overlay.lines().any(|l| l == *line)).count() + unknown;
+    Some((added.into_iter().map(String::from).collect(), removed))
+}
+
+/// Keep what was written to the overlay of `file` while it was overmounted, e.g. by an admin or
+/// a config manager. Call after unmounting. Added lines are appended to the original file as they
+/// were written (see rescan_ssh). Other edits are not applied; the overlay is saved to EDITS_PATH
+/// instead.
+fn preserve_edits(file: &str, principals: bool, settings: &diskstate::Settings) {
+    let saved_lines = fs::read_to_string(added_lines_file(file)).unwrap_or_default();
+    let _ = fs::remove_file(added_lines_file(file));
+    let overlay = match fs::read_to_string(overlay_file(file)) {
+        Ok(overlay) => overlay,
+        Err(_) => return,
+    };
+    let original = match fs::read_to_string(file) {
+        Ok(original) => original,
+        Err(_) => return,
+    };
+    let (command, pty) = ssh_hogged_command(settings);
+    let (added, removed) = match merge_edits(&original, &overlay, &saved_lines, principals, &command, pty) {
+        Some(edits) => edits,
+        None => return,
+    };
+
+    if !added.is_empty() {
+        let mut text = added.join("\n") + "\n";
+        if !original.is_empty() && !original.ends_with('\n') {
+            text.insert(0, '\n');
+        }
+        let appended = fs::OpenOptions::new().append(true).open(file).and_then(|mut f| f.write_all(text.as_bytes()));
+        match appended {
+            Ok(_) => println!("WARN: {} was edited while hogged, kept {} added lines", file, added.len()),
+            Err(err) => println!("WARN: {} was edited while hogged, could not keep the added lines: {}", file, err),
+        }
+    }
+    if removed > 0 {
+        let saved = format!("{}/{}", EDITS_PATH.as_str(), escape(file));
+        let copied = fs::create_dir_all(EDITS_PATH.as_str()).and_then(|_| fs::copy(overlay_file(file), &saved));
+        match copied {
+            Ok(_) => println!("WARN: {} lines were removed or changed in {} while hogged. This was not applied, the edited file is saved in {}", removed, file, saved),
+            Err(err) => println!("WARN: {} lines were removed or changed in {} while hogged. This was not applied and saving the edited file failed: {}", removed, file, err),
+        }
+    }
+}
+
+/// whether `file` is a wrapped keys command or its stash, which have no edits to keep
+fn is_wrapped_command(state: &diskstate::DiskState, file: &str) -> bool {
+    file.starts_with(OVERLAY_PATH.as_str()) || state.wrapped_commands.iter().any(|f| f == file)
+}
+
+/// Forget an overmount after it was unmounted, keeping edits of key and principals files
+fn released(state: &mut diskstate::DiskState, file: &str) {
+    if !is_wrapped_command(state, file) {
+        preserve_edits(file, state.principals_files.iter().any(|f| f == file), &state.settings);
+    }
+    state.overmounts.retain(|f| f != file);
+    state.wrapped_commands.retain(|f| f != file);
+    state.principals_files.retain(|f| f != file);
 }
 
 pub fn release_ssh(state: &mut diskstate::DiskState) {
-    let mut overmounts: Vec<String> = vec![];
-    for file in &state.overmounts {
-        let path = std::path::Path::new(file);
-        if !is_overmounted(file) {
+    let mounts = MountTable::load();
+    // in reverse, like a rollback: the wrapper of a keys command goes before the stash of the
+    // original that it runs
+    for file in state.overmounts.clone().into_iter().rev() {
+        if let Err(err) = unmount(&mounts, &file) {
+            println!("failed to release {}: {:?}", file, err);
             continue;
         }
-        match nix::mount::umount(path) {
-            Err(err) => {
-                println!("failed to release {}: {:?}", file, err);
-                overmounts.push(file.clone());
-            },
-            Ok(_) => {
-                println!("released {}", file);
-            },
-        }
+        // journal every released file, so that an interrupted release does not forget about the
+        // files that are still overmounted
+        released(state, &file);
+        diskstate::store(state);
+    }
+    // overlays that are still mounted stay, so that a later release can retry them
+    if !state.overmounts.is_empty() {
+        return;
     }
     if let Err(err) = util::remove_dir_contents(OVERLAY_PATH.as_str()) {
         println!("WARN: could not remove overlayed files: {}", err);
     }
-    state.overmounts = overmounts;
 }
 
-pub fn do_release(state: &mut diskstate::DiskState) {
-    // always unhog (even when we think its not hogged) to converge towards intended state
-    release_ssh(state);
+/// The modules that a release reverts: all (also inactive ones, to converge towards the intended
+/// state) or only the selected ones
+pub fn release_selection(state: &diskstate::DiskState, only: &[Module]) -> Vec<Module> {
+    modules::known(state).into_iter().filter(|m| only.is_empty() || only.contains(m)).collect()
+}
+
+/// Stop the units of a module without a hog (`hosthog disable`). The module is recorded as active,
+/// so that maintenance does not take its units for leftovers and start them again.
+pub fn do_disable(state: &mut diskstate::DiskState, module: &Module) {
+    if state.active_modules.contains(module) {
+        println!("{} is already disabled", module);
+        return;
+    }
+    diskstate::begin(state, diskstate::Operation::Hog);
+    state.active_modules.push(module.clone());
+    diskstate::journal(state, diskstate::Step::Module(module.clone()));
+    systemd_units::disable_resource(state, module);
+    diskstate::log(state, format!("disable: {}", module));
+    diskstate::commit(state);
+}
+
+/// Start the units of a module again (`hosthog enable`), whether a hog or `disable` stopped them
+pub fn do_enable(state: &mut diskstate::DiskState, module: &Module) {
+    diskstate::begin(state, diskstate::Operation::Release);
+    release_module(state, module);
+    diskstate::log(state, format!("enable: {}", module));
+    diskstate::commit(state);
+}
+
+/// Release only some modules of the hog. The hog itself (and the claim) stays in place.
+fn release_modules(state: &mut diskstate::DiskState, selected: &[Module]) {
+    if state.hogger.is_none() {
+        println!("WARN: host is not hogged");
+    }
+    diskstate::begin(state, diskstate::Operation::Release);
+    for module in release_selection(state, selected) {
+        println!("release module {}", module);
+        release_module(state, &module);
+    }
+    diskstate::commit(state);
+}
+
+pub fn do_release(state: &mut diskstate::DiskState, only: &[Module]) {
+    if !only.is_empty() {
+        return release_modules(state, only);
+    }
+    let hogged = state.hogger.is_some() || !state.overmounts.is_empty() || !state.disabled_systemd_units.is_empty() || state.nologin || !state.frozen_slices.is_empty() || !state.throttled_slices.is_empty();
+    if hogged {
+        diskstate::begin(state, diskstate::Operation::Release);
+    }
+
+    // always unhog all modules (even when we think its not hogged) to converge towards intended
+    // state
+    for module in release_selection(state, &[]) {
+        release_module(state, &module);
+    }
     // delete exclusive claim of user used to issue this hogging
     if let Some(hogger) = &state.hogger {
         state.claims.retain(|claim| claim != hogger);
         state.hogger = None;
     }
+    state.allowed_users.clear();
+    state.active_modules.clear();
+    state.overrides.clear();
 
     // remove "me"s ongoing exclusive claims, if user runs the release subcommand
     if let Some(me) = users::my_username() {
         state.claims.retain(|claim| !(claim.user == me && claim.exclusive));
     }
 
-    // run other modeules
-    systemd_units::enable_resource(state);
+    if hogged {
+        diskstate::commit(state);
+    }
+}
 
+/// Revert the steps of an unfinished hog in reverse order. Steps that can not be reverted stay
+/// recorded in the state so that a later release can retry them.
+fn rollback(state: &mut diskstate::DiskState) {
+    let steps = match &state.transaction {
+        Some(transaction) => transaction.steps.clone(),
+        None => return,
+    };
+    println!("Rolling back {} steps", steps.len());
+    let mounts = MountTable::load();
+    for step in steps.iter().rev() {
+        let reverted = panic::catch_unwind(panic::AssertUnwindSafe(|| match step {
+            diskstate::Step::Overmount(file) => match unmount(&mounts, file) {
+                Ok(_) => {
+                    released(state, file);
+                    let _ = fs::remove_file(overlay_file(file));
+                },
+                Err(err) => println!("failed to release {}: {:?}", file, err),
+            },
+            diskstate::Step::StopUnit(unit) => {
+                systemd_units::enable_units(state, vec![unit.clone()]);
+            },
+            diskstate::Step::Hogger(previous) => {
+                state.hogger = previous.clone();
+            },
+            diskstate::Step::AllowedUsers(previous) => {
+                state.allowed_users = previous.clone();
+            },
+            diskstate::Step::Nologin => {
+                nologin::release(state);
+            },
+            diskstate::Step::NologinAllowedUsers(previous) => {
+                nologin::restore_allowed_users(previous);
+            },
+            diskstate::Step::Freeze(slice) => {
+                systemd_units::thaw_slices(state, vec![slice.clone()]);
+            },
+            diskstate::Step::Throttle(slice) => {
+                systemd_units::unthrottle_slices(state, vec![slice.clone()]);
+            },
+            diskstate::Step::Module(module) => {
+                release_module(state, module);
+            },
+        }));
+        if reverted.is_err() {
+            println!("WARN: failed to roll back {:?}", step);
+        }
+        if let Some(transaction) = &mut state.transaction {
+            transaction.steps.pop();
+        }
+        diskstate::store(state);
+    }
+    if state.hogger.is_none() {
+        state.auth_paths.clear();
+        state.user_coverage.clear();
+    }
+    diskstate::commit(state);
 }
 
-pub fn is_overmounted(file: &str) -> bool {
-    let mounts = std::fs::read_to_string("/proc/mounts").expect("Cant read /proc/mounts");
-    return mounts.contains(file);
+/// Clean up after a hog or release that did not finish. An unfinished hog (or deny) is rolled
+/// back. An unfinished release (or allow) is not: releasing is the safe direction, so we keep what has been released
+/// and leave the rest for the next release. An unfinished rescan keeps what it locked out, since
+/// the hog it belongs to is still up.
+pub fn recover(state: &mut diskstate::DiskState) {
+    match state.transaction.as_ref().map(|t| t.operation) {
+        Some(diskstate::Operation::Hog | diskstate::Operation::Deny) => rollback(state),
+        Some(diskstate::Operation::Release | diskstate::Operation::Allow | diskstate::Operation::Rescan) => diskstate::commit(state),
+        None => {},
+    }
+}
+
+/// Tell the user which parts of a hog are still in place
+pub fn report_leftovers(state: &diskstate::DiskState) {
+    let leftovers: Vec<String> = modules::known(state).iter().flat_map(|m| modules::get(m).leftovers(state)).collect();
+    if leftovers.is_empty() {
+        println!("Nothing is left hogged.");
+        return;
+    }
+    println!("The following is still hogged (run `{} release` to retry):", util::prog_name());
+    for leftover in leftovers {
+        println!("  {}", leftover);
+    }
+}
+
+/// Run a command. If it fails in the middle of a hog or release, recover from the journal and
+/// exit with a report of what is left.
+pub fn guarded<F: FnOnce(&mut diskstate::DiskState)>(state: &mut diskstate::DiskState, f: F) {
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| f(state)));
+    if let Err(err) = result {
+        let operation = match &state.transaction {
+            Some(transaction) => transaction.operation,
+            None => panic::resume_unwind(err),
+        };
+        eprintln!("ERROR: {} failed.", operation);
+        recover(state);
+        report_leftovers(state);
+        std::process::exit(1);
+    }
+}
+
+/// whether `mount` is one of ours: an overlay file, or the stash of a wrapped keys command (which
+/// lives in the overlay directory itself)
+fn is_our_mount(mount: &Mount, file: &str) -> bool {
+    if file.starts_with(OVERLAY_PATH.as_str()) {
+        return true;
+    }
+    // root is relative to the filesystem that contains the overlay directory
+    let root = mount.root.trim_start_matches('/');
+    !root.is_empty() && std::path::Path::new(&overlay_file(file)).ends_with(root)
+}
+
+/// whether our overlay is what is visible at `file`
+pub fn is_ours(mounts: &MountTable, file: &str) -> bool {
+    mounts.at(file).last().is_some_and(|mount| is_our_mount(mount, file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::prelude::*;
+
+    fn user(name: &str, uid: u32) -> User {
+        User {
+            name: name.to_string(),
+            uid,
+            gid: uid,
+            home: format!("/home/{}", name),
+            shell: String::from("/bin/bash"),
+        }
+    }
+
+    fn hogged(modules: &[Module]) -> diskstate::DiskState {
+        let mut state = diskstate::load_default();
+        state.hogger = Some(diskstate::Claim {
+            timeout: Local::now() + chrono::Duration::hours(1),
+            soft_timeout: None,
+            exclusive: true,
+            user: String::from("alice"),
+            comment: String::new(),
+        });
+        state.allowed_users = vec![String::from("root"), String::from("alice")];
+        state.active_modules = modules.to_vec();
+        state
+    }
+
+    #[test]
+    fn ssh_module_locks_out() {
+        let state = hogged(&[Module::Ssh, Module::Timers]);
+        assert!(locks_out_user(&state, &user("bob", 1002)));
+        assert!(!locks_out_user(&state, &user("alice", 1001)));
+        let mut released = diskstate::load_default();
+        released.active_modules = vec![Module::Ssh];
+        assert!(!locks_out_user(&released, &user("bob", 1002)));
+    }
+
+    #[test]
+    fn release_only_ssh_lets_users_in() {
+        // what `release --only ssh` leaves behind
+        let mut state = hogged(&[Module::Ssh, Module::Timers]);
+        state.active_modules.retain(|m| *m != Module::Ssh);
+        assert!(!locks_out_user(&state, &user("bob", 1002)));
+        // `hog --throttle` never locks anyone out
+        assert!(!locks_out_user(&hogged(&[Module::Throttle]), &user("bob", 1002)));
+    }
+
+    #[test]
+    fn keeps_files_shared_with_exempt_accounts() {
+        let settings = diskstate::load_default().settings;
+        let shared_file = String::from("/nonexistent/authorized_keys.d/deploy");
+        let mut monitoring = user("monitoring", 999);
+        monitoring.shell = String::from("/usr/sbin/nologin");
+        let users = vec![user("bob", 1002), user("carol", 1003), monitoring];
+        let files = |u: &User| match u.name.as_str() {
+            "bob" | "monitoring" => vec![shared_file.clone()],
+            name => vec![format!("/nonexistent/authorized_keys.d/{}", name)],
+        };
+        let (locked_out, shared_with) = split_users(users, &[String::from("carol")], &settings, files);
+        assert_eq!(locked_out.iter().map(|u| u.name.as_str()).collect::<Vec<&str>>(), vec!["bob"]);
+
+        let mut shared = vec![];
+        let mut refused = vec![];
+        let targets = resolve_targets(
+            locked_out.iter().map(|u| (u, shared_file.clone())).collect(),
+            &shared_with,
+            &mut shared,
+            &mut refused,
+            &MountTable::load(),
+        );
+        assert!(targets.is_empty());
+        assert_eq!(shared, vec![(shared_file, vec![String::from("monitoring")])]);
+        assert!(refused.is_empty());
+    }
+
+    const COMMAND: &str = "sudo hosthog status";
+    const ALICE: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAlice alice@laptop";
+    const BOB: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIBob bob@laptop";
+
+    fn merge(original: &str, overlay: &str, saved_lines: &str, principals: bool) -> Option<(Vec<String>, usize)> {
+        merge_edits(original, overlay, saved_lines, principals, COMMAND, false)
+    }
+
+    #[test]
+    fn unchanged_overlay_has_no_edits() {
+        let original = format!("{}\n", ALICE);
+        let overlay = authorized_keys::restrict(&original, COMMAND, false);
+        assert_eq!(merge(&original, &overlay, "", false), None);
+    }
+
+    #[test]
+    fn keeps_added_keys() {
+        let original = format!("{}\n", ALICE);
+        // appended after the last rescan
+        let overlay = authorized_keys::restrict(&original, COMMAND, false) + BOB + "\n";
+        assert_eq!(merge(&original, &overlay, "", false), Some((vec![BOB.to_string()], 0)));
+        // restricted by a rescan, which saved it as it was written
+        let overlay = authorized_keys::restrict(&format!("{}\n{}\n", ALICE, BOB), COMMAND, false);
+        assert_eq!(merge(&original, &overlay, &format!("{}\n", BOB), false), Some((vec![BOB.to_string()], 0)));
+    }
+
+    #[test]
+    fn does_not_apply_removed_keys() {
+        let original = format!("{}\n{}\n", ALICE, BOB);
+        let overlay = authorized_keys::restrict(&format!("{}\n", ALICE), COMMAND, false);
+        assert_eq!(merge(&original, &overlay, "", false), Some((vec![], 1)));
+        // a restricted line that we do not know the original of counts as changed
+        let overlay = authorized_keys::restrict(&format!("{}\n{}\n", ALICE, BOB), COMMAND, false);
+        assert_eq!(merge(&format!("{}\n", ALICE), &overlay, "", false), Some((vec![], 1)));
+    }
+
+    #[test]
+    fn merges_principals_files() {
+        let original = "alice\n";
+        let overlay = authorized_keys::restrict_principals(original, COMMAND, false);
+        assert_eq!(merge(original, &overlay, "", true), None);
+        // a key file restriction of the same content differs
+        assert_ne!(authorized_keys::restrict(original, COMMAND, false), overlay);
+        let overlay = authorized_keys::restrict_principals("alice\nbob\n", COMMAND, false);
+        assert_eq!(merge(original, &overlay, "bob\n", true), Some((vec![String::from("bob")], 0)));
+    }
 }
@@ -0,0 +1,95 @@
+use crate::authorized_keys;
+use crate::diskstate::{self, DiskState};
+use crate::hog;
+use crate::util;
+use crate::KeysCommandArgs;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::process::{Command, Stdio};
+
+/// Find out which argument of an AuthorizedKeysCommand or AuthorizedPrincipalsCommand tells us the
+/// user that logs in. Returns the index (within the arguments after the binary) and whether it is
+/// a uid.
+fn user_arg(template: &[String]) -> Option<(usize, bool)> {
+    // without arguments, sshd passes the user name as the only argument
+    if template.len() == 1 {
+        return Some((0, false));
+    }
+    let args = &template[1..];
+    if let Some(i) = args.iter().position(|a| a == "%u") {
+        return Some((i, false));
+    }
+    args.iter().position(|a| a == "%U").map(|i| (i, true))
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Replace the binary of an AuthorizedKeysCommand (or AuthorizedPrincipalsCommand with
+/// `principals`) by a wrapper that restricts the keys it returns for users that are locked out.
+/// The original binary stays available through a bind mount in the overlay directory.
+pub fn wrap(state: &mut DiskState, template: &[String], principals: bool) -> Result<(), String> {
+    // mount over the file itself, not a link to it: the mount would land on the target and we
+    // would not find it at the recorded path
+    let binary = &fs::canonicalize(&template[0])
+        .map(|path| path.to_string_lossy().into_owned())
+        .map_err(|e| format!("{}: {}", template[0], e))?;
+    if state.overmounts.contains(binary) {
+        return Ok(());
+    }
+    let (index, uid) = user_arg(template)
+        .ok_or_else(|| format!("can not tell which argument of {} is the user", template.join(" ")))?;
+
+    let stash = format!("{}.orig", hog::overlay_file(binary));
+    fs::create_dir_all(hog::OVERLAY_PATH.as_str()).map_err(|e| e.to_string())?;
+    fs::write(&stash, "").map_err(|e| format!("{}: {}", stash, e))?;
+    hog::bind_mount(binary, &stash).map_err(|e| format!("{}: {}", stash, e))?;
+    state.overmounts.push(stash.clone());
+    diskstate::journal(state, diskstate::Step::Overmount(stash.clone()));
+
+    let mut command = vec![shell_quote(&util::prog()), String::from("keys-command")];
+    if principals {
+        command.push(String::from("--principals"));
+    }
+    if uid {
+        command.push(String::from("--uid"));
+    }
+    command.push(format!("--user-arg {}", index));
+    command.push(shell_quote(&stash));
+    let wrapper = format!("#!/bin/sh\nexec {} \"$@\"\n", command.join(" "));
+    let wrapper_file = hog::overlay_file(binary);
+    fs::write(&wrapper_file, wrapper).map_err(|e| format!("{}: {}", wrapper_file, e))?;
+    fs::set_permissions(&wrapper_file, fs::Permissions::from_mode(0o755)).map_err(|e| format!("{}: {}", wrapper_file, e))?;
+    hog::bind_mount(&wrapper_file, binary).map_err(|e| format!("{}: {}", binary, e))?;
+    state.overmounts.push(binary.clone());
+    state.wrapped_commands.push(binary.clone());
+    diskstate::journal(state, diskstate::Step::Overmount(binary.clone()));
+    Ok(())
+}
+
+/// Called by sshd through the wrapper: run the original command and restrict its output if the
+/// user is locked out.
+pub fn do_keys_command(cmd: KeysCommandArgs, state: &DiskState) {
+    let out = Command::new(&cmd.original)
+        .args(&cmd.args)
+        .stderr(Stdio::inherit())
+        .output()
+        .unwrap_or_else(|e| panic!("can not run {}: {}", cmd.original, e));
+    let stdout = String::from_utf8_lossy(&out.stdout);
+
+    let user = cmd.args.get(cmd.user_arg).cloned().unwrap_or_default();
+    let user = match cmd.uid {
+        true => user.parse::<u32>().map(util::get_username).unwrap_or_default(),
+        false => user,
+    };
+    let locked_out = hog::locked_out(state, &user);
+
+    let (command, pty) = hog::ssh_hogged_command(&state.settings);
+    match (locked_out, cmd.principals) {
+        (false, _) => print!("{}", stdout),
+        (true, false) => println!("{}", authorized_keys::restrict(&stdout, &command, pty)),
+        (true, true) => println!("{}", authorized_keys::restrict_principals(&stdout, &command, pty)),
+    }
+    std::process::exit(out.status.code().unwrap_or(1));
+}
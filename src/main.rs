@@ -1,6 +1,7 @@
 use clap::{Args, Parser, Subcommand};
 use std::process::Command;
 use chrono::prelude::*;
+use diskstate::Module;
 
 mod hog;
 mod diskstate;
@@ -8,6 +9,19 @@ mod users;
 mod claims;
 mod util;
 mod systemd_units;
+mod settings;
+mod sshd_config;
+mod authorized_keys;
+mod keys_command;
+mod pam;
+mod nologin;
+mod evict;
+mod modules;
+mod dry_run;
+mod gate;
+mod watch;
+mod namespace;
+mod mountinfo;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -46,11 +60,101 @@ pub struct ClaimCommand {
 }
 
 
-#[derive(clap::ValueEnum, Clone)]
-enum Resource {
-    SystemdTimers,
-    // SshKeys, // cant be done via this API because it relies on cli params regarding which users
-    // to lock out
+#[derive(Args)]
+pub struct HogCommand {
+    /// Block ssh login for all users except the ones specified here (default: your user and
+    /// root). Specify -u multiple times to add more users.
+    #[arg(short, long)]
+    users: Vec<String>,
+    /// Also allow the members of these unix groups. Specify -g multiple times to add more groups.
+    #[arg(short, long)]
+    groups: Vec<String>,
+    /// Freeze the processes of all other users until the hog is released
+    #[arg(long)]
+    freeze: bool,
+    #[command(flatten)]
+    throttle: ThrottleArgs,
+    /// Tell all other users to log out and terminate their sessions after this grace period
+    /// (e.g. 5min)
+    #[arg(long, value_name = "GRACE", value_parser = evict::parse_grace)]
+    evict: Option<std::time::Duration>,
+    /// Only hog these modules (default: ssh,timers,units)
+    #[arg(long, value_delimiter = ',', conflicts_with = "except", value_parser = modules::parse_arg)]
+    only: Option<Vec<Module>>,
+    /// Hog the default modules except these
+    #[arg(long, value_delimiter = ',', value_parser = modules::parse_arg)]
+    except: Vec<Module>,
+    #[command(flatten)]
+    dry_run: DryRunArgs,
+}
+
+#[derive(Args)]
+pub struct DryRunArgs {
+    /// Only show what would be done
+    #[arg(long)]
+    dry_run: bool,
+    /// Show the dry run as JSON
+    #[arg(long, requires = "dry_run")]
+    json: bool,
+}
+
+#[derive(Args)]
+pub struct ThrottleArgs {
+    /// Soft hog: throttle the resources of all other users instead of locking them out
+    #[arg(long)]
+    throttle: bool,
+    /// CPUWeight of other users while throttled (1-10000, default of systemd: 100)
+    #[arg(long, default_value_t = 1, requires = "throttle", value_parser = clap::value_parser!(u64).range(1..=10000))]
+    cpu_weight: u64,
+    /// IOWeight of other users while throttled (1-10000, default of systemd: 100)
+    #[arg(long, default_value_t = 1, requires = "throttle", value_parser = clap::value_parser!(u64).range(1..=10000))]
+    io_weight: u64,
+    /// CPUQuota of other users in percent of one CPU (e.g. 50)
+    #[arg(long, requires = "throttle", value_parser = clap::value_parser!(u64).range(1..))]
+    cpu_quota: Option<u64>,
+    /// AllowedCPUs of other users (e.g. 0-1,8)
+    #[arg(long, requires = "throttle")]
+    allowed_cpus: Option<String>,
+}
+
+#[derive(Subcommand)]
+pub enum SettingsCommand {
+    /// Show one setting (default: all settings)
+    Get {
+        key: Option<String>,
+    },
+    /// Replace the value(s) of a setting
+    Set {
+        key: String,
+        values: Vec<String>,
+    },
+    /// Add a value to a list setting
+    Add {
+        key: String,
+        value: String,
+    },
+    /// Remove a value from a list setting
+    Remove {
+        key: String,
+        value: String,
+    },
+}
+
+#[derive(Args)]
+pub struct KeysCommandArgs {
+    /// The wrapped command is an AuthorizedPrincipalsCommand
+    #[arg(long)]
+    principals: bool,
+    /// The user argument is a uid
+    #[arg(long)]
+    uid: bool,
+    /// Which of the arguments is the user
+    #[arg(long)]
+    user_arg: usize,
+    /// original AuthorizedKeysCommand
+    original: String,
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    args: Vec<String>,
 }
 
 
@@ -67,13 +171,26 @@ enum Commands {
         claim: ClaimCommand,
     },
     /// prematurely release a claim (removes all of your hogs and exclusive claims)
-    Release {},
+    Release {
+        /// Only release these modules and keep the rest of the hog
+        #[arg(long, value_delimiter = ',', value_parser = modules::parse_arg)]
+        only: Vec<Module>,
+        #[command(flatten)]
+        dry_run: DryRunArgs,
+    },
     /// Hog the entire host (others will hate you)
     Hog {
-        /// Block ssh login for all users except the ones specified here (default: your user and
-        /// root). Specify -u multiple times to add more users.
-        #[arg(short, long)]
-        users: Vec<String>,
+        #[command(flatten)]
+        hog: HogCommand,
+    },
+    /// Let a user or the members of a unix group in while the host stays hogged
+    Allow {
+        /// user or group
+        name: String,
+    },
+    /// Lock a user out again while the host stays hogged
+    Deny {
+        user: String,
     },
     /// post a message to all logged in users
     ///
@@ -84,6 +201,11 @@ enum Commands {
         /// message to post
         message: Vec<String>
     },
+    /// Inspect or change settings (e.g. authorized_keys_file)
+    Settings {
+        #[command(subcommand)]
+        command: SettingsCommand,
+    },
     /// List all logged in users
     ///
     /// Checks:
@@ -95,39 +217,88 @@ enum Commands {
     },
 
     #[command(hide(true))]
-    /// disable/hog a system resource  (this disables e.g. ssh keys)
+    /// disable/hog a system resource  (only timers and units, use `hog --only` for the others)
     Disable {
-        resource: Resource,
+        #[arg(value_parser = modules::parse_arg)]
+        module: Module,
     },
     #[command(hide(true))]
-    /// enable/release a system resource  (this enables e.g. ssh keys)
+    /// enable/release a system resource  (only timers and units, use `release --only` for the others)
     Enable {
-        resource: Resource,
+        #[arg(value_parser = modules::parse_arg)]
+        module: Module,
     },
     #[command(hide(true))]
     // Internal command used to trigger updating the list of claims and hogs
-    Maintenance {}
+    Maintenance {
+        #[command(flatten)]
+        dry_run: DryRunArgs,
+    },
+    /// Deny locked out users while hogged. Run by PAM via pam_exec in the account phase.
+    ///
+    /// Add to /etc/pam.d/<service>:
+    /// account required pam_exec.so quiet stdout /path/to/hosthog pam-check
+    PamCheck {},
+    #[command(hide(true))]
+    // Forced command for locked out users with the soft gate. Runs via sudo and drops to the user
+    // that logs in before it starts a shell.
+    Gate {
+        /// what ssh was asked to run (SSH_ORIGINAL_COMMAND), empty for a login shell
+        #[arg(long, allow_hyphen_values = true)]
+        command: Option<String>,
+    },
+    #[command(hide(true))]
+    // Internal command that locks out new key files and users while hogged. Runs as a systemd unit.
+    Watch {},
+    #[command(hide(true))]
+    // Internal command that sshd runs instead of an AuthorizedKeysCommand while hogged
+    KeysCommand {
+        #[command(flatten)]
+        cmd: KeysCommandArgs,
+    },
+}
+
+/// whether a command may store the state (and has to take the state lock). Read-only commands
+/// do not wait for a hog or the watcher to finish.
+fn modifies_state(command: &Option<Commands>) -> bool {
+    match command {
+        None | Some(Commands::Status { .. } | Commands::Post { .. } | Commands::Users {} | Commands::KeysCommand { .. }) => false,
+        Some(Commands::Hog { hog }) => !hog.dry_run.dry_run,
+        Some(Commands::Release { dry_run, .. } | Commands::Maintenance { dry_run }) => !dry_run.dry_run,
+        Some(_) => true,
+    }
 }
 
 fn show_status_verbose(_cmd: StatusCommand, state: &diskstate::DiskState) {
     println!("{}", serde_yaml::to_string(&state).unwrap());
+    pam::print_status(&state.settings);
 }
 
 fn show_status(_cmd: StatusCommand, state: &diskstate::DiskState) {
-    if state.overmounts.len() > 0 {
-        println!("");
-        if let Some(claim) = &state.hogger {
-            println!("{}", hog::ssh_hogged_message(claim));
+    if let Some(claim) = &state.hogger {
+        println!();
+        println!("{}", hog::ssh_hogged_message(claim));
+        let names: Vec<String> = state.active_modules.iter().map(|m| m.to_string()).collect();
+        println!("Hogged modules: {}", names.join(", "));
+        println!("Allowed users: {}", state.allowed_users.join(", "));
+        for module in &state.active_modules {
+            for line in modules::get(module).status(state) {
+                println!("{}", line);
+            }
+        }
+        println!();
+    } else {
+        if !state.active_modules.is_empty() {
+            let names: Vec<String> = state.active_modules.iter().map(|m| m.to_string()).collect();
+            println!();
+            println!("Disabled without a hog: {}", names.join(", "));
+        }
+        let leftovers: Vec<String> = modules::reconcilable(state).iter().flat_map(|m| modules::get(m).leftovers(state)).collect();
+        if !leftovers.is_empty() {
+            println!();
+            println!("WARN: the host is not hogged, but these are still in place: {}", leftovers.join(", "));
+            println!();
         }
-        let active_overmounts = state.overmounts.iter().filter(|file| hog::is_overmounted(file)).collect::<Vec<&String>>().len();
-        // println!("The following ssh keys are temporarily disabled:");
-        // for file in &state.overmounts {
-        //     if hog::is_overmounted(file) {
-        //         active_overmounts += 1;
-        //     }
-        // }
-        println!("{} keys were disabled to hog, {} keys are still disabled", state.overmounts.len(), active_overmounts);
-        println!("");
     }
 
     println!("Active claims:");
@@ -209,72 +380,149 @@ fn parse_timeout(timeout: &str) -> DateTime<Local> {
 }
 
 fn do_maintenance(mut state: &mut diskstate::DiskState) {
+    gate::import(state);
+    if let Some(transaction) = &state.transaction {
+        println!("WARN: the {} started at {} did not finish.", transaction.operation, transaction.started);
+        hog::recover(state);
+        hog::report_leftovers(state);
+    }
     let mut needs_release = false;
     diskstate::maintenance(&mut state, &mut needs_release);
     if needs_release {
-        hog::do_release(&mut state);
+        hog::do_release(&mut state, &[]);
     }
-    if state.hogger.is_none() && state.overmounts.len() != 0 {
-        println!("WARN: host is not hogged, yet there still seem to be unexpected overmounts. Attempting to remove.");
-        hog::release_ssh(state);
+    if state.hogger.is_none() {
+        for module in modules::reconcilable(state) {
+            modules::get(&module).reconcile(state);
+        }
     }
 }
 
 fn main() {
     let cli = Cli::parse();
 
+    // runs on every login: must not touch (or fail on) the state file
+    if let Some(Commands::PamCheck {}) = cli.command {
+        pam::do_pam_check();
+    }
+    if let Some(Commands::Gate { command }) = cli.command {
+        gate::do_gate(command);
+    }
+    // everything below may mount, or check mounts
+    namespace::enter_host();
+    // runs until the host is released and loads the state itself
+    if let Some(Commands::Watch {}) = cli.command {
+        watch::do_watch();
+        return;
+    }
+
+    // held until we exit, so that the state we store at the end is not outdated (`hog --evict`
+    // releases it while it waits)
+    let mut lock = match modifies_state(&cli.command) {
+        true => diskstate::lock(),
+        false => None,
+    };
     let _original_state = diskstate::load();
     let mut state = diskstate::load();
     if let Err(e) = diskstate::check_version(&state) {
         panic!("{}", e);
     }
 
-    match cli.command {
+    hog::guarded(&mut state, |state| match cli.command {
         Some(Commands::Status { status }) if !status.verbose => {
-            show_status(status, &mut state);
+            show_status(status, state);
         }
         Some(Commands::Status { status }) if status.verbose => {
-            show_status_verbose(status, &mut state);
+            show_status_verbose(status, state);
         }
         Some(Commands::Claim { claim }) => {
-            do_maintenance(&mut state);
-            claims::do_claim(&claim, &mut state);
+            do_maintenance(state);
+            claims::do_claim(&claim, state);
+        }
+        Some(Commands::Release { only, dry_run }) if dry_run.dry_run => {
+            dry_run::print(&dry_run::release(state, &only), dry_run.json);
         }
-        Some(Commands::Release { }) => {
-            do_maintenance(&mut state);
-            hog::do_release(&mut state);
+        Some(Commands::Release { only, dry_run: _ }) => {
+            do_maintenance(state);
+            hog::do_release(state, &only);
         }
-        Some(Commands::Hog{ users }) => {
-            do_maintenance(&mut state);
-            hog::do_hog(users, &mut state)
+        Some(Commands::Hog{ hog }) if hog.dry_run.dry_run => {
+            dry_run::print(&dry_run::hog(&hog, state), hog.dry_run.json);
+        },
+        Some(Commands::Hog{ hog }) => {
+            do_maintenance(state);
+            hog::do_hog(hog, state, &mut lock)
+        },
+        Some(Commands::Allow { name }) => {
+            do_maintenance(state);
+            hog::do_allow(&name, state);
+        },
+        Some(Commands::Deny { user }) => {
+            do_maintenance(state);
+            hog::do_deny(&user, state);
         },
         Some(Commands::Post{ message }) => {
             do_post(message)
         },
+        Some(Commands::Settings { command }) => {
+            settings::do_settings(command, state);
+        },
         Some(Commands::Users { }) => {
             users::do_list_users();
         },
-        Some(Commands::Disable{ resource: Resource::SystemdTimers {}}) => {
-            systemd_units::disable_resource(&mut state);
+        Some(Commands::Disable{ module: module @ (Module::Timers | Module::Units) }) => {
+            do_maintenance(state);
+            hog::do_disable(state, &module);
         },
-        Some(Commands::Enable{ resource: Resource::SystemdTimers {}}) => {
-            systemd_units::enable_resource(&mut state);
+        Some(Commands::Enable{ module: module @ (Module::Timers | Module::Units) }) => {
+            do_maintenance(state);
+            hog::do_enable(state, &module);
         },
-        Some(Commands::Maintenance { }) => {
-            do_maintenance(&mut state);
+        Some(Commands::Disable{ module } | Commands::Enable{ module }) => {
+            panic!("{} depends on the users to lock out. Use `{} hog --only {}` or `{} release --only {}`.", module, util::prog_name(), module, util::prog_name(), module);
+        },
+        Some(Commands::Maintenance { dry_run }) if dry_run.dry_run => {
+            dry_run::print(&dry_run::maintenance(state), dry_run.json);
+        },
+        Some(Commands::Maintenance { dry_run: _ }) => {
+            do_maintenance(state);
+        },
+        Some(Commands::KeysCommand { cmd }) => {
+            keys_command::do_keys_command(cmd, state);
         },
         None => {
-            show_status(StatusCommand::default(), &mut state);
+            show_status(StatusCommand::default(), state);
             println!(
                 "See more options with: {} help",
                 util::prog_name()
             );
         }
         _ => unimplemented!()
-    };
+    });
 
     if _original_state != state {
         // println!("state changed, storing");
         diskstate::store(&state);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gate_command(args: &[&str]) -> Option<String> {
+        match Cli::try_parse_from(args).map(|cli| cli.command) {
+            Ok(Some(Commands::Gate { command })) => command,
+            Ok(_) => panic!("not the gate"),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn gate_takes_commands_with_hyphens() {
+        assert_eq!(gate_command(&["hosthog", "gate", "--command", "-c ls"]), Some(String::from("-c ls")));
+        assert_eq!(gate_command(&["hosthog", "gate", "--command=-c ls"]), Some(String::from("-c ls")));
+        assert_eq!(gate_command(&["hosthog", "gate", "--command", "--help"]), Some(String::from("--help")));
+        assert_eq!(gate_command(&["hosthog", "gate", "--command", ""]), Some(String::new()));
+    }
+}
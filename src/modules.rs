@@ -0,0 +1,201 @@
+use crate::diskstate::{self, Claim, DiskState, Module, ALL_MODULES};
+use crate::hog;
+use crate::systemd_units;
+use crate::HogCommand;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::process::{Command, Stdio};
+
+/// A mechanism that a hog can lock down. Each module owns its part of the DiskState and journals
+/// every change it makes, so that an interrupted hog can be rolled back.
+pub trait HogModule {
+    /// Lock down. Expects allowed_users and hogger to be set already.
+    fn apply(&self, state: &mut DiskState, cmd: &HogCommand);
+    /// Undo everything this module changed. Must also work after an apply that did not finish.
+    fn revert(&self, state: &mut DiskState);
+    /// Let these users in while the hog stays up. allowed_users already contains them.
+    fn allow(&self, state: &mut DiskState, users: &[String]);
+    /// Lock these users out as well. allowed_users no longer contains them.
+    fn deny(&self, state: &mut DiskState, users: &[String]);
+    /// Lines for `status` while the module is active
+    fn status(&self, state: &DiskState) -> Vec<String>;
+    /// Changes of this module that are still in place, for reports after a failure
+    fn leftovers(&self, state: &DiskState) -> Vec<String>;
+    /// What apply would do, without changing anything
+    fn dry_run(&self, state: &DiskState, cmd: &HogCommand) -> Vec<String>;
+
+    /// Clean up changes that are still in place although the host is not hogged
+    fn reconcile(&self, state: &mut DiskState) {
+        let leftovers = self.leftovers(state);
+        if !leftovers.is_empty() {
+            println!("WARN: host is not hogged, yet these are still in place: {}. Attempting to release.", leftovers.join(", "));
+            self.revert(state);
+        }
+    }
+}
+
+pub fn get(module: &Module) -> Box<dyn HogModule> {
+    match module {
+        Module::Ssh => Box::new(hog::SshKeys),
+        Module::Timers | Module::Units => Box::new(systemd_units::SystemdUnits(module.clone())),
+        Module::Freeze => Box::new(systemd_units::Freeze),
+        Module::Throttle => Box::new(systemd_units::Throttle),
+        Module::External(name) => Box::new(External { name: name.clone() }),
+    }
+}
+
+/// whether `hosthog-module-<name>` is an executable in PATH
+fn external_installed(name: &str) -> bool {
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    std::env::split_paths(&path).any(|dir| {
+        std::fs::metadata(dir.join(format!("hosthog-module-{}", name)))
+            .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+    })
+}
+
+/// Parse a module name given on the command line. Unlike the state, this rejects external modules
+/// that are not installed, so that a typo does not fail in the middle of a hog.
+pub fn parse_arg(name: &str) -> Result<Module, String> {
+    match name.parse()? {
+        Module::External(name) if !external_installed(&name) => {
+            let builtin: Vec<String> = ALL_MODULES.iter().map(|m| m.to_string()).collect();
+            Err(format!("unknown module {} (built-in modules: {}; hosthog-module-{} is not in PATH)", name, builtin.join(", "), name))
+        }
+        module => Ok(module),
+    }
+}
+
+/// All built-in modules and the external modules that the state knows about
+pub fn known(state: &DiskState) -> Vec<Module> {
+    let mut modules = ALL_MODULES.to_vec();
+    let external = state.active_modules.iter().cloned().chain(state.module_states.keys().map(|name| Module::External(name.clone())));
+    for module in external {
+        if !modules.contains(&module) {
+            modules.push(module);
+        }
+    }
+    modules
+}
+
+/// The modules whose changes are leftovers while the host is not hogged. Modules that were
+/// disabled by hand (`hosthog disable`) stay active without a hog.
+pub fn reconcilable(state: &DiskState) -> Vec<Module> {
+    known(state).into_iter().filter(|m| !state.active_modules.contains(m)).collect()
+}
+
+/// What we send to an external module on stdin
+#[derive(Serialize)]
+struct Request<'a> {
+    action: &'a str,
+    hogger: &'a Option<Claim>,
+    allowed_users: &'a Vec<String>,
+    /// users that are allowed or denied (allow and deny only)
+    users: &'a [String],
+    /// what the module returned last time, null if it has no state
+    state: Option<&'a serde_json::Value>,
+}
+
+/// What an external module prints on stdout
+#[derive(Deserialize, Default)]
+struct Response {
+    /// new state of the module. Null once it has nothing left to revert.
+    #[serde(default)]
+    state: Option<serde_json::Value>,
+    /// lines to show to the user (status, leftovers or dry-run)
+    #[serde(default)]
+    lines: Vec<String>,
+}
+
+/// A module provided by the executable `hosthog-module-<name>` in PATH. It is called with the
+/// action (apply, revert, allow, deny, status, dry-run) as argument and a JSON request on stdin,
+/// and answers with a JSON response on stdout. A non-zero exit code fails the action.
+pub struct External {
+    name: String,
+}
+
+impl External {
+    fn call(&self, action: &str, state: &DiskState, users: &[String]) -> Result<Response, String> {
+        let binary = format!("hosthog-module-{}", self.name);
+        let request = Request {
+            action,
+            hogger: &state.hogger,
+            allowed_users: &state.allowed_users,
+            users,
+            state: state.module_states.get(&self.name),
+        };
+        let mut child = Command::new(&binary)
+            .arg(action)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|e| format!("{}: {}", binary, e))?;
+        let input = serde_json::to_vec(&request).expect("can not serialize module request");
+        child.stdin.take().expect("stdin is piped").write_all(&input).map_err(|e| format!("{}: {}", binary, e))?;
+        let out = child.wait_with_output().map_err(|e| format!("{}: {}", binary, e))?;
+        if !out.status.success() {
+            return Err(format!("{} {} exited with {}", binary, action, out.status));
+        }
+        if out.stdout.iter().all(|b| b.is_ascii_whitespace()) {
+            return Ok(Response::default());
+        }
+        serde_json::from_slice(&out.stdout).map_err(|e| format!("{} {}: invalid response: {}", binary, action, e))
+    }
+
+    /// run an action that changes the system and remember the state the module returns
+    fn change(&self, action: &str, state: &mut DiskState, users: &[String]) {
+        let response = self.call(action, state, users).unwrap_or_else(|e| panic!("module {}: {}", self.name, e));
+        for line in &response.lines {
+            println!("{}: {}", self.name, line);
+        }
+        match response.state {
+            Some(module_state) => state.module_states.insert(self.name.clone(), module_state),
+            None => state.module_states.remove(&self.name),
+        };
+        diskstate::store(state);
+    }
+
+    fn lines(&self, action: &str, state: &DiskState) -> Vec<String> {
+        match self.call(action, state, &[]) {
+            Ok(response) => response.lines,
+            Err(e) => vec![format!("failed to ask module {}: {}", self.name, e)],
+        }
+    }
+}
+
+impl HogModule for External {
+    fn apply(&self, state: &mut DiskState, _cmd: &HogCommand) {
+        self.change("apply", state, &[]);
+    }
+
+    fn revert(&self, state: &mut DiskState) {
+        if !state.module_states.contains_key(&self.name) && !state.active_modules.contains(&Module::External(self.name.clone())) {
+            return;
+        }
+        self.change("revert", state, &[]);
+    }
+
+    fn allow(&self, state: &mut DiskState, users: &[String]) {
+        self.change("allow", state, users);
+    }
+
+    fn deny(&self, state: &mut DiskState, users: &[String]) {
+        self.change("deny", state, users);
+    }
+
+    fn status(&self, state: &DiskState) -> Vec<String> {
+        self.lines("status", state)
+    }
+
+    fn leftovers(&self, state: &DiskState) -> Vec<String> {
+        match state.module_states.contains_key(&self.name) {
+            true => vec![format!("module {}", self.name)],
+            false => vec![],
+        }
+    }
+
+    fn dry_run(&self, state: &DiskState, _cmd: &HogCommand) -> Vec<String> {
+        self.lines("dry-run", state)
+    }
+}
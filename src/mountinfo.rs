@@ -0,0 +1,169 @@
+use std::fs;
+
+/// A line of /proc/<pid>/mountinfo (see man proc_pid_mountinfo)
+#[derive(Debug)]
+pub struct Mount {
+    pub id: u32,
+    pub parent: u32,
+    /// the path within the source filesystem that is mounted. For bind mounts, the file that was
+    /// mounted.
+    pub root: String,
+    pub target: String,
+    /// optional fields like `shared:1` or `master:2`
+    pub propagation: Vec<String>,
+    pub fstype: String,
+    pub source: String,
+}
+
+impl Mount {
+    /// whether mounts below it propagate to other mount namespaces
+    pub fn is_shared(&self) -> bool {
+        self.propagation.iter().any(|field| field.starts_with("shared:"))
+    }
+
+    pub fn describe(&self) -> String {
+        format!("{}:{} ({}, mount {})", self.source, self.root, self.fstype, self.id)
+    }
+}
+
+/// undo the octal escapes of the kernel (`\040` for a space)
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes.get(i + 1..i + 4).and_then(|digits| u8::from_str_radix(std::str::from_utf8(digits).ok()?, 8).ok());
+        match (bytes[i], octal) {
+            (b'\\', Some(byte)) => {
+                out.push(byte);
+                i += 4;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn parse(line: &str) -> Option<Mount> {
+    let (mount, filesystem) = line.split_once(" - ")?;
+    let mut fields = mount.split(' ');
+    let id = fields.next()?.parse().ok()?;
+    let parent = fields.next()?.parse().ok()?;
+    let _device = fields.next()?;
+    let root = unescape(fields.next()?);
+    let target = unescape(fields.next()?);
+    let _options = fields.next()?;
+    let propagation = fields.map(String::from).collect();
+    let mut filesystem = filesystem.split(' ');
+    let fstype = filesystem.next()?.to_string();
+    let source = unescape(filesystem.next().unwrap_or(""));
+    Some(Mount { id, parent, root, target, propagation, fstype, source })
+}
+
+/// The mounts of a mount namespace, read once per operation
+pub struct MountTable {
+    mounts: Vec<Mount>,
+}
+
+impl MountTable {
+    /// the mounts that we see
+    pub fn load() -> MountTable {
+        MountTable::of("self").expect("Cant read /proc/self/mountinfo")
+    }
+
+    /// the mounts that a process sees. None if we can not read them.
+    pub fn of(pid: &str) -> Option<MountTable> {
+        let text = fs::read_to_string(format!("/proc/{}/mountinfo", pid)).ok()?;
+        Some(MountTable { mounts: text.lines().filter_map(parse).collect() })
+    }
+
+    /// the mounts at `target`, the visible one last
+    pub fn at(&self, target: &str) -> Vec<&Mount> {
+        self.mounts.iter().filter(|mount| mount.target == target).collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Mount> {
+        self.mounts.iter()
+    }
+
+    pub fn is_mounted(&self, target: &str) -> bool {
+        self.mounts.iter().any(|mount| mount.target == target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(text: &str) -> MountTable {
+        MountTable { mounts: text.lines().filter_map(parse).collect() }
+    }
+
+    #[test]
+    fn unescapes_octal() {
+        assert_eq!(unescape("/home/a\\040b"), "/home/a b");
+        assert_eq!(unescape("tab\\011here"), "tab\there");
+        assert_eq!(unescape("back\\134slash"), "back\\slash");
+        // not an escape
+        assert_eq!(unescape("a\\9b"), "a\\9b");
+        assert_eq!(unescape("end\\"), "end\\");
+    }
+
+    #[test]
+    fn parses_optional_fields() {
+        let mount = parse("29 1 254:0 / / rw,relatime shared:1 master:2 - ext4 /dev/vda rw").unwrap();
+        assert_eq!((mount.id, mount.parent), (29, 1));
+        assert_eq!((mount.root.as_str(), mount.target.as_str()), ("/", "/"));
+        assert_eq!(mount.propagation, vec!["shared:1", "master:2"]);
+        assert!(mount.is_shared());
+        assert_eq!((mount.fstype.as_str(), mount.source.as_str()), ("ext4", "/dev/vda"));
+    }
+
+    #[test]
+    fn parses_without_optional_fields() {
+        let mount = parse("43 28 254:0 /var/lib/hosthog/overlay/_home_alice_.ssh_authorized__keys /home/alice/.ssh/authorized_keys rw,relatime - ext4 /dev/vda rw").unwrap();
+        assert!(mount.propagation.is_empty());
+        assert!(!mount.is_shared());
+        assert_eq!(mount.root, "/var/lib/hosthog/overlay/_home_alice_.ssh_authorized__keys");
+        assert_eq!(mount.target, "/home/alice/.ssh/authorized_keys");
+        assert_eq!(mount.describe(), "/dev/vda:/var/lib/hosthog/overlay/_home_alice_.ssh_authorized__keys (ext4, mount 43)");
+    }
+
+    #[test]
+    fn parses_spaces_in_paths() {
+        let mount = parse("50 28 0:45 /srv/my\\040keys /home/a\\040b/.ssh/authorized\\011keys rw shared:5 - nfs4 server:/export\\040dir rw").unwrap();
+        assert_eq!(mount.root, "/srv/my keys");
+        assert_eq!(mount.target, "/home/a b/.ssh/authorized\tkeys");
+        assert_eq!(mount.source, "server:/export dir");
+        assert_eq!(mount.propagation, vec!["shared:5"]);
+    }
+
+    #[test]
+    fn skips_malformed_lines() {
+        assert!(parse("").is_none());
+        assert!(parse("29 1 254:0 / / rw").is_none());
+        assert!(parse("x 1 254:0 / / rw - ext4 /dev/vda rw").is_none());
+    }
+
+    #[test]
+    fn finds_stacked_mounts() {
+        let mounts = table(concat!(
+            "29 1 254:0 / / rw,relatime shared:1 - ext4 /dev/vda rw\n",
+            "43 29 254:0 /overlay/a /home/alice/.ssh/authorized_keys rw,relatime shared:1 - ext4 /dev/vda rw\n",
+            "44 43 254:0 /other/a /home/alice/.ssh/authorized_keys rw,relatime shared:1 - ext4 /dev/vda rw\n",
+            "45 29 0:46 / /home/a\\040b rw - tmpfs tmpfs rw\n",
+        ));
+        let stack = mounts.at("/home/alice/.ssh/authorized_keys");
+        assert_eq!(stack.iter().map(|m| m.id).collect::<Vec<u32>>(), vec![43, 44]);
+        // the visible one is last and sits on top of the one below
+        assert_eq!(stack.last().unwrap().parent, stack[0].id);
+        assert!(mounts.is_mounted("/home/a b"));
+        assert!(!mounts.is_mounted("/home/a\\040b"));
+        assert!(!mounts.is_mounted("/home/alice/.ssh"));
+        assert!(mounts.at("/home/bob/.ssh/authorized_keys").is_empty());
+        assert_eq!(mounts.iter().count(), 4);
+    }
+}
@@ -0,0 +1,75 @@
+use crate::hog;
+use crate::mountinfo::MountTable;
+use std::fs;
+use std::os::fd::AsRawFd;
+
+/// The mount namespace of a process, e.g. `mnt:[4026531841]`. None if we can not look it up.
+fn mount_namespace(pid: &str) -> Option<String> {
+    fs::read_link(format!("/proc/{}/ns/mnt", pid)).ok().map(|ns| ns.to_string_lossy().into_owned())
+}
+
+/// Move into the mount namespace of PID 1 if we run in another one (e.g. a systemd service with
+/// ProtectHome= or PrivateMounts=). Otherwise our overmounts would be invisible to sshd. Must be
+/// called before any threads are started.
+pub fn enter_host() {
+    let (ours, host) = match (mount_namespace("self"), mount_namespace("1")) {
+        (Some(ours), Some(host)) => (ours, host),
+        _ => return,
+    };
+    if ours == host {
+        return;
+    }
+    let ns = fs::File::open("/proc/1/ns/mnt")
+        .unwrap_or_else(|e| panic!("hosthog runs in a private mount namespace ({}), sshd would not see its mounts. Can not enter the host namespace: {}", ours, e));
+    // safe because the file descriptor stays open until setns returns
+    if unsafe { libc::setns(ns.as_raw_fd(), libc::CLONE_NEWNS) } != 0 {
+        panic!(
+            "hosthog runs in a private mount namespace ({}), sshd would not see its mounts. Can not enter the host namespace: {}. Run it outside of containers and services with ProtectHome= or PrivateMounts=.",
+            ours,
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+fn comm(pid: &str) -> String {
+    fs::read_to_string(format!("/proc/{}/comm", pid)).unwrap_or_default().trim().to_string()
+}
+
+fn parent(pid: &str) -> Option<String> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // the command in parentheses may contain spaces
+    stat.rsplit_once(')')?.1.split_whitespace().nth(1).map(String::from)
+}
+
+/// The pid of the sshd that accepts connections (not one of its session processes)
+fn sshd_pid() -> Option<String> {
+    let entries = fs::read_dir("/proc").ok()?;
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|pid| pid.chars().all(|c| c.is_ascii_digit()))
+        .find(|pid| comm(pid) == "sshd" && parent(pid).is_some_and(|ppid| comm(&ppid) != "sshd"))
+}
+
+pub struct SshdView {
+    pub pid: String,
+    /// sshd runs in another mount namespace than we do
+    pub other_namespace: bool,
+    /// number of files that sshd sees our overlays at
+    pub seen: usize,
+    /// number of our overlays that do not propagate to other mount namespaces
+    pub private: usize,
+}
+
+/// At which of `files` sshd sees our overlays. None if sshd is not running.
+pub fn overmounts_seen_by_sshd(files: &[String]) -> Option<SshdView> {
+    let pid = sshd_pid()?;
+    let sshd_mounts = MountTable::of(&pid)?;
+    let ours = MountTable::load();
+    Some(SshdView {
+        other_namespace: mount_namespace(&pid) != mount_namespace("self"),
+        seen: files.iter().filter(|file| hog::is_ours(&sshd_mounts, file)).count(),
+        private: files.iter().filter(|file| ours.at(file).last().is_some_and(|mount| !mount.is_shared())).count(),
+        pid,
+    })
+}
@@ -0,0 +1,76 @@
+use crate::diskstate::{self, DiskState};
+use crate::hog;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+/// pam_nologin denies all logins except root while this file exists
+const NOLOGIN: &str = "/run/nologin";
+/// users that the PAM exception lets through
+const ALLOWED_USERS_FILE: &str = "/run/hosthog/allowed_users";
+
+/// the line admins add directly in front of the `account` line of pam_nologin.so to let allowed
+/// users skip it. sshd skips the `auth` phase for public key logins, so it must be in `account`.
+pub fn pam_exception_line() -> String {
+    format!("account [success=1 default=ignore] pam_listfile.so item=user sense=allow file={} onerr=fail", ALLOWED_USERS_FILE)
+}
+
+fn remove(file: &str) -> std::io::Result<()> {
+    match fs::remove_file(file) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Lock out everyone except root and the allowed users by writing /run/nologin
+pub fn hog(state: &mut DiskState) {
+    if !state.nologin && Path::new(NOLOGIN).exists() {
+        panic!("{} already exists and was not created by us. Not overwriting it.", NOLOGIN);
+    }
+    let parent = Path::new(ALLOWED_USERS_FILE).parent().unwrap();
+    fs::create_dir_all(parent).expect("failed to create /run/hosthog");
+    // pam_nologin denies everyone, so the users that the lockout settings leave alone are allowed
+    // as well
+    let mut allowed = state.allowed_users.clone();
+    allowed.extend(hog::all_users(&state.settings).into_iter().filter(|u| !state.settings.locks_out(u)).map(|u| u.name));
+    // allow and deny rewrite it while hogged, a rollback has to restore it
+    if state.nologin {
+        let previous = fs::read_to_string(ALLOWED_USERS_FILE).ok();
+        diskstate::journal(state, diskstate::Step::NologinAllowedUsers(previous));
+    }
+    fs::write(ALLOWED_USERS_FILE, allowed.join("\n") + "\n").expect("failed to write allowed users");
+    let message = state.hogger.as_ref().map(hog::ssh_hogged_message).unwrap_or_default();
+    fs::write(NOLOGIN, message + "\n").expect("failed to write /run/nologin");
+    if !state.nologin {
+        state.nologin = true;
+        diskstate::journal(state, diskstate::Step::Nologin);
+    }
+    println!("nologin: logins are disabled for everyone except root and {}", state.allowed_users.join(", "));
+    println!("nologin: allowed users need `{}` directly in front of `account required pam_nologin.so` in /etc/pam.d/sshd (and the other services)", pam_exception_line());
+}
+
+/// Put back the users that the PAM exception lets through, as journaled before a rewrite
+pub fn restore_allowed_users(previous: &Option<String>) {
+    let restored = match previous {
+        Some(text) => fs::write(ALLOWED_USERS_FILE, text),
+        None => remove(ALLOWED_USERS_FILE),
+    };
+    if let Err(e) = restored {
+        println!("failed to restore {}: {}", ALLOWED_USERS_FILE, e);
+    }
+}
+
+pub fn release(state: &mut DiskState) {
+    if !state.nologin {
+        return;
+    }
+    for file in [NOLOGIN, ALLOWED_USERS_FILE] {
+        if let Err(e) = remove(file) {
+            println!("failed to remove {}: {}", file, e);
+            return;
+        }
+    }
+    println!("released {}", NOLOGIN);
+    state.nologin = false;
+    diskstate::store(state);
+}
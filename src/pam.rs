@@ -0,0 +1,102 @@
+use crate::diskstate::{self, Backend, DiskState, Gate, Settings};
+use crate::hog;
+use crate::util;
+
+const PAM_DIR: &str = "/etc/pam.d";
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// the line admins add to the PAM configuration of a service
+pub fn hook_line() -> String {
+    format!("account required pam_exec.so quiet stdout {} pam-check", util::prog())
+}
+
+fn service_enabled(settings: &Settings, service: &str) -> bool {
+    settings.pam_services.iter().any(|pattern| util::wildcard_match(pattern, service))
+}
+
+/// Why a user is denied by the hog, if it is denied. `publickey` tells whether sshd
+/// authenticated the user with a key.
+pub fn denied(state: &DiskState, user: &str, service: &str, publickey: bool) -> Option<String> {
+    let hogger = state.hogger.as_ref()?;
+    if !hog::locked_out(state, user) || !service_enabled(&state.settings, service) {
+        return None;
+    }
+    // key logins have to get through to the soft gate. Passwords are denied as usual.
+    if state.settings.gate == Gate::Soft && state.settings.backend == Backend::Overmount && service == "sshd" && publickey {
+        return None;
+    }
+    Some(hog::ssh_hogged_message(hogger))
+}
+
+/// Called by pam_exec in the account phase. Exits non-zero to deny the login. Never denies if
+/// the state can not be read, so that a broken hosthog can not lock everyone out.
+pub fn do_pam_check() -> ! {
+    let user = std::env::var("PAM_USER").unwrap_or_default();
+    let service = std::env::var("PAM_SERVICE").unwrap_or_default();
+    // sshd lists the successful authentication methods in the PAM environment, which pam_exec
+    // passes on, e.g. `publickey ssh-ed25519 AAAA...`
+    let publickey = std::env::var("SSH_AUTH_INFO_0").is_ok_and(|info| info.lines().any(|line| line.starts_with("publickey ")));
+    let state = match diskstate::try_load() {
+        Ok(state) => state,
+        Err(e) => {
+            eprintln!("hosthog: can not check hog: {}", e);
+            std::process::exit(0);
+        }
+    };
+    match denied(&state, &user, &service, publickey) {
+        Some(message) => {
+            println!("{}", message);
+            std::process::exit(1);
+        }
+        None => std::process::exit(0),
+    }
+}
+
+/// whether a PAM config file (or a file it includes) runs our hook in the account phase
+fn hook_installed_in(file: &str, depth: usize) -> bool {
+    if depth > MAX_INCLUDE_DEPTH {
+        return false;
+    }
+    let text = match std::fs::read_to_string(format!("{}/{}", PAM_DIR, file)) {
+        Ok(text) => text,
+        Err(_) => return false,
+    };
+    text.lines().any(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            ["@include", included, ..] => hook_installed_in(included, depth + 1),
+            [kind, control, included, ..] if kind.trim_start_matches('-') == "account" && (*control == "include" || *control == "substack") => {
+                hook_installed_in(included, depth + 1)
+            }
+            [kind, ..] if kind.trim_start_matches('-') == "account" => {
+                line.contains("pam_exec.so") && line.contains("pam-check")
+            }
+            _ => false,
+        }
+    })
+}
+
+pub fn hook_installed(service: &str) -> bool {
+    hook_installed_in(service, 0)
+}
+
+/// For which of the configured services that exist on this host the hook is installed
+pub fn status(settings: &Settings) -> Vec<String> {
+    let (installed, missing): (Vec<&String>, Vec<&String>) = settings
+        .pam_services
+        .iter()
+        .filter(|service| std::path::Path::new(&format!("{}/{}", PAM_DIR, service)).is_file())
+        .partition(|service| hook_installed(service));
+    let list = |services: Vec<&String>| services.iter().map(|s| s.as_str()).collect::<Vec<&str>>().join(", ");
+    let mut lines = vec![format!("PAM hook installed for: {}", list(installed))];
+    if !missing.is_empty() {
+        lines.push(format!("PAM hook missing for: {} (add `{}` to their files in {})", list(missing), hook_line(), PAM_DIR));
+    }
+    lines
+}
+
+pub fn print_status(settings: &Settings) {
+    for line in status(settings) {
+        println!("{}", line);
+    }
+}
@@ -0,0 +1,218 @@
+use crate::diskstate::{self, DiskState, Settings};
+use crate::SettingsCommand;
+use serde_json::Value;
+
+/// Settings that are used to hog the host or decide whom it locks out. Changing them while the
+/// host is hogged would leave us unable to tell what the active hog has done, and pam-check would
+/// disagree with the mounts.
+const HOG_SETTINGS: &[&str] = &[
+    "authorized_keys_file",
+    "backend",
+    "gate",
+    "lockout_uids",
+    "lockout_skip_shells",
+    "lockout_exclude",
+    "exempt_users",
+    "home_roots",
+];
+
+fn validate(settings: &Settings) -> Result<(), String> {
+    if settings.authorized_keys_file.is_empty() {
+        return Err(String::from("authorized_keys_file must not be empty"));
+    }
+    for pattern in &settings.authorized_keys_file {
+        diskstate::validate_authorized_keys_file(pattern)?;
+    }
+    for range in &settings.lockout_uids {
+        diskstate::parse_uid_range(range)?;
+    }
+    if settings.exempt_users.is_empty() {
+        println!("WARN: exempt_users is empty. A hog can now lock out everyone, including root.");
+    }
+    Ok(())
+}
+
+fn to_map(settings: &Settings) -> serde_json::Map<String, Value> {
+    match serde_json::to_value(settings).expect("settings are always serializable") {
+        Value::Object(map) => map,
+        _ => unreachable!(),
+    }
+}
+
+fn get(settings: &Settings, key: &str) -> Result<Value, String> {
+    to_map(settings)
+        .remove(key)
+        .ok_or_else(|| format!("unknown setting {}", key))
+}
+
+/// parse command line values into the same json type as the current value of the setting
+fn parse_value(current: &Value, key: &str, values: &[String]) -> Result<Value, String> {
+    match current {
+        Value::Array(_) => Ok(Value::Array(values.iter().map(|v| Value::String(v.clone())).collect())),
+        _ if values.len() != 1 => Err(format!("{} takes exactly one value", key)),
+        Value::String(_) => Ok(Value::String(values[0].clone())),
+        Value::Bool(_) => values[0]
+            .parse::<bool>()
+            .map(Value::Bool)
+            .map_err(|e| format!("{}: {}", key, e)),
+        _ => serde_json::from_str(&values[0]).map_err(|e| format!("{}: {}", key, e)),
+    }
+}
+
+/// apply a change to a copy of the settings and return the changed copy
+fn change(settings: &Settings, command: &SettingsCommand) -> Result<Settings, String> {
+    let mut map = to_map(settings);
+    let (key, new) = match command {
+        SettingsCommand::Get { .. } => unreachable!(),
+        SettingsCommand::Set { key, values } => {
+            let current = get(settings, key)?;
+            (key, parse_value(&current, key, values)?)
+        }
+        SettingsCommand::Add { key, value } => match get(settings, key)? {
+            Value::Array(mut list) => {
+                let value = Value::String(value.clone());
+                if list.contains(&value) {
+                    return Err(format!("{} already contains {}", key, value));
+                }
+                list.push(value);
+                (key, Value::Array(list))
+            }
+            _ => return Err(format!("{} is not a list", key)),
+        },
+        SettingsCommand::Remove { key, value } => match get(settings, key)? {
+            Value::Array(mut list) => {
+                let value = Value::String(value.clone());
+                if !list.contains(&value) {
+                    return Err(format!("{} does not contain {}", key, value));
+                }
+                list.retain(|v| *v != value);
+                (key, Value::Array(list))
+            }
+            _ => return Err(format!("{} is not a list", key)),
+        },
+    };
+    map.insert(key.clone(), new);
+    let changed: Settings = serde_json::from_value(Value::Object(map)).map_err(|e| format!("{}: {}", key, e))?;
+    validate(&changed)?;
+    Ok(changed)
+}
+
+fn print(value: &Value) {
+    print!("{}", serde_yaml::to_string(value).unwrap());
+}
+
+pub fn do_settings(command: SettingsCommand, state: &mut DiskState) {
+    let key = match &command {
+        SettingsCommand::Get { key: None } => {
+            print(&Value::Object(to_map(&state.settings)));
+            return;
+        }
+        SettingsCommand::Get { key: Some(key) } => {
+            match get(&state.settings, key) {
+                Ok(value) => print(&value),
+                Err(e) => panic!("{}", e),
+            }
+            return;
+        }
+        SettingsCommand::Set { key, .. } | SettingsCommand::Add { key, .. } | SettingsCommand::Remove { key, .. } => key.clone(),
+    };
+
+    if state.hogger.is_some() && HOG_SETTINGS.contains(&key.as_str()) {
+        panic!("Can not change {} while the host is hogged. Release first.", key);
+    }
+
+    let changed = match change(&state.settings, &command) {
+        Ok(changed) => changed,
+        Err(e) => panic!("Invalid setting: {}", e),
+    };
+    if changed == state.settings {
+        println!("{} is unchanged", key);
+        return;
+    }
+
+    let old = get(&state.settings, &key).unwrap();
+    let new = get(&changed, &key).unwrap();
+    state.settings = changed;
+    diskstate::log(state, format!("settings: changed {} from {} to {}", key, old, new));
+    print(&new);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::prelude::*;
+
+    fn set(key: &str, values: &[&str]) -> SettingsCommand {
+        SettingsCommand::Set { key: key.to_string(), values: values.iter().map(|v| v.to_string()).collect() }
+    }
+
+    fn hogged() -> DiskState {
+        let mut state = diskstate::load_default();
+        state.hogger = Some(diskstate::Claim {
+            timeout: Local::now() + chrono::Duration::hours(1),
+            soft_timeout: None,
+            exclusive: true,
+            user: String::from("alice"),
+            comment: String::new(),
+        });
+        state
+    }
+
+    #[test]
+    fn sets_and_gets() {
+        let settings = diskstate::load_default().settings;
+        let changed = change(&settings, &set("authorized_keys_file", &["/etc/ssh/keys/%u"])).unwrap();
+        assert_eq!(changed.authorized_keys_file, vec![String::from("/etc/ssh/keys/%u")]);
+        assert_eq!(get(&changed, "authorized_keys_file"), Ok(serde_json::json!(["/etc/ssh/keys/%u"])));
+        let changed = change(&settings, &set("gate", &["soft"])).unwrap();
+        assert_eq!(changed.gate, diskstate::Gate::Soft);
+        assert_eq!(get(&changed, "gate"), Ok(serde_json::json!("soft")));
+        // everything else is left alone
+        assert_eq!(Settings { gate: settings.gate, ..changed }, settings);
+    }
+
+    #[test]
+    fn adds_and_removes() {
+        let settings = diskstate::load_default().settings;
+        let add = SettingsCommand::Add { key: String::from("lockout_exclude"), value: String::from("monitoring") };
+        let added = change(&settings, &add).unwrap();
+        assert_eq!(added.lockout_exclude, vec![String::from("monitoring")]);
+        assert!(change(&added, &add).is_err());
+        let remove = SettingsCommand::Remove { key: String::from("lockout_exclude"), value: String::from("monitoring") };
+        assert_eq!(change(&added, &remove).unwrap(), settings);
+        assert!(change(&settings, &remove).is_err());
+        let not_a_list = SettingsCommand::Add { key: String::from("gate"), value: String::from("soft") };
+        assert!(change(&settings, &not_a_list).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let settings = diskstate::load_default().settings;
+        assert!(change(&settings, &set("no_such_setting", &["x"])).is_err());
+        assert!(change(&settings, &set("authorized_keys_file", &[])).is_err());
+        assert!(change(&settings, &set("authorized_keys_file", &["%h/.ssh/%x"])).is_err());
+        assert!(change(&settings, &set("lockout_uids", &["1000-abc"])).is_err());
+        assert!(change(&settings, &set("backend", &["nfs"])).is_err());
+        assert!(change(&settings, &set("gate", &["soft", "hard"])).is_err());
+        assert!(change(&settings, &set("lockout_uids", &["1000-59999", "0"])).is_ok());
+    }
+
+    #[test]
+    fn changes_settings_while_hogged() {
+        let mut state = hogged();
+        do_settings(set("pam_services", &["sshd"]), &mut state);
+        assert_eq!(state.settings.pam_services, vec![String::from("sshd")]);
+        assert!(state.history.last().is_some_and(|entry| entry.event.starts_with("settings: changed pam_services")));
+    }
+
+    #[test]
+    fn refuses_hog_settings_while_hogged() {
+        for key in HOG_SETTINGS {
+            let mut state = hogged();
+            let value = if *key == "backend" { "nologin" } else { "x" };
+            let refused = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| do_settings(set(key, &[value]), &mut state)));
+            assert!(refused.is_err(), "{} was changed while hogged", key);
+            assert_eq!(state.settings, hogged().settings);
+        }
+    }
+}
@@ -0,0 +1,478 @@
+use crate::hog::User;
+use crate::util;
+use once_cell::sync::Lazy;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::process::Command;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+const SSHD_CONFIG: &str = "/etc/ssh/sshd_config";
+const SSHD_CONFIG_DIR: &str = "/etc/ssh";
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Compile-time defaults of OpenSSH for the options we look at
+const DEFAULTS: &[(&str, &[&str])] = &[
+    ("authorizedkeysfile", &[".ssh/authorized_keys", ".ssh/authorized_keys2"]),
+];
+
+/// (lowercase keyword, arguments) in the order they appear
+type Options = Vec<(String, Vec<String>)>;
+
+/// Options of the global section or of one Match block
+#[derive(Debug)]
+struct Block {
+    /// (criterion, argument) pairs of the Match line. None for the global section.
+    criteria: Option<Vec<(String, String)>>,
+    options: Options,
+}
+
+impl Block {
+    fn first(&self, keyword: &str) -> Option<&Vec<String>> {
+        self.options.iter().find(|(k, _)| k == keyword).map(|(_, args)| args)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Matches {
+    Yes,
+    No,
+    /// depends on the connection (Host, Address, ...) which we don't know in advance
+    Maybe,
+}
+
+/// Our view of the sshd configuration
+pub struct SshdConfig {
+    blocks: Vec<Block>,
+    /// whether `sshd -T` can tell us the effective configuration of users
+    sshd_t: bool,
+    /// whether the config file could be parsed
+    parsed: bool,
+    /// unix groups of the users we looked at, for Match Group
+    groups: RefCell<HashMap<String, Vec<String>>>,
+}
+
+/// Files whose changes may change the output of `sshd -T`: the config files and the users and
+/// groups that Match blocks refer to
+const SSHD_T_DEPENDS_ON: &[&str] = &["/etc/passwd", "/etc/group"];
+
+/// Output of `sshd -T`, shared by users that the same Match blocks apply to. The watcher rescans
+/// often, so it is kept across loads as long as the files it depends on did not change.
+#[derive(Default)]
+struct SshdTCache {
+    files: Vec<(String, Option<SystemTime>)>,
+    /// by `SshdConfig::sshd_t_key`
+    options: HashMap<String, Option<Options>>,
+}
+
+static SSHD_T_CACHE: Lazy<Mutex<SshdTCache>> = Lazy::new(Default::default);
+
+/// split a config line into arguments. Double quotes group arguments, an unquoted `#` starts a
+/// comment.
+fn split_args(line: &str) -> Vec<String> {
+    let mut args = vec![];
+    let mut arg = String::new();
+    let mut in_arg = false;
+    let mut quoted = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_arg = true;
+            }
+            '#' if !quoted && !in_arg => break,
+            c if c.is_whitespace() && !quoted => {
+                if in_arg {
+                    args.push(std::mem::take(&mut arg));
+                    in_arg = false;
+                }
+            }
+            c => {
+                arg.push(c);
+                in_arg = true;
+            }
+        }
+    }
+    if in_arg {
+        args.push(arg);
+    }
+    args
+}
+
+/// split `Keyword args`, `Keyword=args` or `Keyword = args`
+fn split_keyword(line: &str) -> (String, Vec<String>) {
+    let line = line.trim();
+    let end = line.find(|c: char| c.is_whitespace() || c == '=').unwrap_or(line.len());
+    let keyword = line[..end].to_lowercase();
+    let rest = line[end..].trim_start();
+    let rest = rest.strip_prefix('=').unwrap_or(rest);
+    (keyword, split_args(rest))
+}
+
+/// Parse a config file into `blocks`. `files` collects the files that were read.
+fn parse_file(path: &str, blocks: &mut Vec<Block>, files: &mut Vec<String>, depth: usize) -> Result<(), String> {
+    if depth > MAX_INCLUDE_DEPTH {
+        return Err(format!("{}: too many nested includes", path));
+    }
+    files.push(path.to_string());
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    for line in text.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let (keyword, args) = split_keyword(trimmed);
+        match keyword.as_str() {
+            "match" => {
+                let criteria = args
+                    .chunks(2)
+                    .map(|pair| (pair[0].to_lowercase(), pair.get(1).cloned().unwrap_or_default()))
+                    .collect();
+                blocks.push(Block { criteria: Some(criteria), options: vec![] });
+            }
+            "include" => {
+                let criteria = blocks.last().and_then(|b| b.criteria.clone());
+                for pattern in args {
+                    let pattern = match pattern.starts_with('/') {
+                        true => pattern,
+                        false => format!("{}/{}", SSHD_CONFIG_DIR, pattern),
+                    };
+                    let paths = glob::glob(&pattern).map_err(|e| format!("{}: {}", pattern, e))?;
+                    for included in paths.flatten() {
+                        parse_file(&included.to_string_lossy(), blocks, files, depth + 1)?;
+                    }
+                }
+                // a Match block in an included file ends with that file
+                if blocks.last().map(|b| &b.criteria) != Some(&criteria) {
+                    blocks.push(Block { criteria, options: vec![] });
+                }
+            }
+            _ => blocks.last_mut().expect("there is always a global block").options.push((keyword, args)),
+        }
+    }
+    Ok(())
+}
+
+fn evaluate(criteria: &[(String, String)], user: &User, groups: &[String]) -> Matches {
+    let mut result = Matches::Yes;
+    for (criterion, arg) in criteria {
+        let matches = match criterion.as_str() {
+            "all" => Matches::Yes,
+            "user" => match util::match_pattern_list(&user.name, arg) {
+                true => Matches::Yes,
+                false => Matches::No,
+            },
+            "group" => match groups.iter().any(|g| util::match_pattern_list(g, arg)) {
+                true => Matches::Yes,
+                false => Matches::No,
+            },
+            _ => Matches::Maybe,
+        };
+        match matches {
+            Matches::No => return Matches::No,
+            Matches::Maybe => result = Matches::Maybe,
+            Matches::Yes => {},
+        }
+    }
+    result
+}
+
+/// ask sshd for the effective configuration of a user
+fn sshd_t(user: &str) -> Option<Options> {
+    let spec = format!("user={},host=localhost,addr=127.0.0.1", user);
+    let out = Command::new("sshd")
+        .args(["-T", "-f", SSHD_CONFIG, "-C", &spec])
+        .output()
+        .ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    let options = String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .map(split_keyword)
+        .collect();
+    Some(options)
+}
+
+/// the output of `sshd -T` for a user, from the cache if possible
+fn cached_sshd_t(key: &str, user: &str) -> Option<Options> {
+    let mut cache = SSHD_T_CACHE.lock().unwrap();
+    cache.options.entry(key.to_string()).or_insert_with(|| sshd_t(user)).clone()
+}
+
+impl SshdConfig {
+    pub fn load() -> SshdConfig {
+        let mut blocks = vec![Block { criteria: None, options: vec![] }];
+        let mut files = vec![];
+        let parsed = parse_file(SSHD_CONFIG, &mut blocks, &mut files, 0);
+        if let Err(e) = &parsed {
+            eprintln!("WARN: could not parse sshd config: {}", e);
+        }
+        files.extend(SSHD_T_DEPENDS_ON.iter().map(|f| f.to_string()));
+        let files: Vec<(String, Option<SystemTime>)> = files
+            .into_iter()
+            .map(|f| {
+                let modified = std::fs::metadata(&f).and_then(|m| m.modified()).ok();
+                (f, modified)
+            })
+            .collect();
+        {
+            let mut cache = SSHD_T_CACHE.lock().unwrap();
+            if cache.files != files {
+                *cache = SshdTCache { files, options: HashMap::new() };
+            }
+        }
+        let sshd_t = cached_sshd_t("user root", "root").is_some();
+        SshdConfig { blocks, sshd_t, parsed: parsed.is_ok(), groups: RefCell::default() }
+    }
+
+    /// the groups of a user, looked up once
+    fn groups(&self, user: &User) -> Vec<String> {
+        self.groups
+            .borrow_mut()
+            .entry(user.name.clone())
+            .or_insert_with(|| util::get_groups(&user.name, user.gid))
+            .clone()
+    }
+
+    /// `sshd -T` only depends on the user through the Match blocks that apply to it. So users for
+    /// which they evaluate the same share the output, unless we could not parse the config.
+    fn sshd_t_key(&self, user: &User, groups: &[String]) -> String {
+        if !self.parsed {
+            return format!("user {}", user.name);
+        }
+        let matches: String = self
+            .blocks
+            .iter()
+            .filter_map(|block| block.criteria.as_ref())
+            .map(|criteria| match evaluate(criteria, user, groups) {
+                Matches::Yes => 'y',
+                Matches::No => 'n',
+                Matches::Maybe => 'm',
+            })
+            .collect();
+        format!("matches {}", matches)
+    }
+
+    /// whether we know the configuration, and not only the defaults of OpenSSH
+    pub fn known(&self) -> bool {
+        self.sshd_t || self.parsed
+    }
+
+    /// All values of an option that may be effective for this user. sshd uses the first value of
+    /// the first matching Match block, or else the first value of the global section. Match blocks
+    /// that depend on the connection may or may not apply, so we return their values as well.
+    pub fn values(&self, user: &User, keyword: &str) -> Vec<Vec<String>> {
+        let mut values = vec![];
+        let groups = self.groups(user);
+        let mut decided = false;
+        for block in &self.blocks {
+            let criteria = match &block.criteria {
+                Some(criteria) => criteria,
+                None => continue,
+            };
+            let matches = evaluate(criteria, user, &groups);
+            if matches == Matches::No {
+                continue;
+            }
+            if let Some(args) = block.first(keyword) {
+                values.push(args.clone());
+                if matches == Matches::Yes {
+                    decided = true;
+                    break;
+                }
+            }
+        }
+        if !decided {
+            let global = self.blocks.iter().filter(|b| b.criteria.is_none()).find_map(|b| b.first(keyword));
+            let default = DEFAULTS
+                .iter()
+                .find(|(k, _)| *k == keyword)
+                .map(|(_, args)| args.iter().map(|a| a.to_string()).collect());
+            if let Some(args) = global.cloned().or(default) {
+                values.push(args);
+            }
+        }
+        if self.sshd_t {
+            for (k, args) in cached_sshd_t(&self.sshd_t_key(user, &groups), &user.name).iter().flatten() {
+                if k == keyword && !values.contains(args) {
+                    values.push(args.clone());
+                }
+            }
+        }
+        values
+    }
+
+    /// AuthorizedKeysFile patterns that sshd may use for this user
+    pub fn authorized_keys_files(&self, user: &User) -> Vec<String> {
+        let mut patterns: Vec<String> = vec![];
+        for pattern in self.values(user, "authorizedkeysfile").into_iter().flatten() {
+            if !patterns.contains(&pattern) {
+                patterns.push(pattern);
+            }
+        }
+        patterns
+    }
+
+    /// Like `values`, but for options with a single argument. `none` is left out.
+    pub fn paths(&self, user: &User, keyword: &str) -> Vec<String> {
+        self.values(user, keyword)
+            .into_iter()
+            .filter_map(|args| args.into_iter().next())
+            .filter(|path| path != "none")
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(name: &str) -> User {
+        User {
+            name: name.to_string(),
+            uid: 1001,
+            gid: 1001,
+            home: format!("/home/{}", name),
+            shell: String::from("/bin/bash"),
+        }
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    fn criteria(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(c, a)| (c.to_string(), a.to_string())).collect()
+    }
+
+    /// write config files (name, content) to a fresh directory and parse the first one
+    fn parse(name: &str, files: &[(&str, &str)]) -> SshdConfig {
+        let dir = std::env::temp_dir().join(format!("hosthog-sshd-config-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (file, text) in files {
+            std::fs::write(dir.join(file), text.replace("$DIR", &dir.to_string_lossy())).unwrap();
+        }
+        let mut blocks = vec![Block { criteria: None, options: vec![] }];
+        parse_file(&dir.join(files[0].0).to_string_lossy(), &mut blocks, &mut vec![], 0).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        SshdConfig { blocks, sshd_t: false, parsed: true, groups: RefCell::default() }
+    }
+
+    #[test]
+    fn splits_args() {
+        assert_eq!(split_args("a b\t c"), strings(&["a", "b", "c"]));
+        assert_eq!(split_args("\"with space\" b"), strings(&["with space", "b"]));
+        assert_eq!(split_args("a\"b c\"d"), strings(&["ab cd"]));
+        assert_eq!(split_args("\"\""), strings(&[""]));
+        assert_eq!(split_args("a # comment"), strings(&["a"]));
+        assert_eq!(split_args("\"a # b\" c"), strings(&["a # b", "c"]));
+        assert_eq!(split_args("a#b"), strings(&["a#b"]));
+    }
+
+    #[test]
+    fn splits_keyword() {
+        let keys = strings(&[".ssh/authorized_keys", "/etc/ssh/keys/%u"]);
+        assert_eq!(split_keyword("AuthorizedKeysFile .ssh/authorized_keys /etc/ssh/keys/%u"), (String::from("authorizedkeysfile"), keys.clone()));
+        assert_eq!(split_keyword("AuthorizedKeysFile=.ssh/authorized_keys /etc/ssh/keys/%u"), (String::from("authorizedkeysfile"), keys.clone()));
+        assert_eq!(split_keyword("  AuthorizedKeysFile = .ssh/authorized_keys /etc/ssh/keys/%u"), (String::from("authorizedkeysfile"), keys));
+        assert_eq!(split_keyword("AuthorizedKeysCommand \"/usr/bin/keys lookup\" %u"), (String::from("authorizedkeyscommand"), strings(&["/usr/bin/keys lookup", "%u"])));
+        assert_eq!(split_keyword("UsePAM"), (String::from("usepam"), vec![]));
+    }
+
+    #[test]
+    fn evaluates_user_and_group() {
+        let alice = user("alice");
+        let staff = strings(&["alice", "staff"]);
+        assert_eq!(evaluate(&criteria(&[("all", "")]), &alice, &[]), Matches::Yes);
+        assert_eq!(evaluate(&criteria(&[("user", "alice,bob")]), &alice, &[]), Matches::Yes);
+        assert_eq!(evaluate(&criteria(&[("user", "a*")]), &alice, &[]), Matches::Yes);
+        assert_eq!(evaluate(&criteria(&[("user", "bob")]), &alice, &[]), Matches::No);
+        assert_eq!(evaluate(&criteria(&[("group", "staff")]), &alice, &staff), Matches::Yes);
+        assert_eq!(evaluate(&criteria(&[("group", "wheel")]), &alice, &staff), Matches::No);
+        // all criteria have to match
+        assert_eq!(evaluate(&criteria(&[("user", "alice"), ("group", "wheel")]), &alice, &staff), Matches::No);
+    }
+
+    #[test]
+    fn evaluates_negation() {
+        let alice = user("alice");
+        assert_eq!(evaluate(&criteria(&[("user", "*,!alice")]), &alice, &[]), Matches::No);
+        assert_eq!(evaluate(&criteria(&[("user", "*,!bob")]), &alice, &[]), Matches::Yes);
+        // a negation alone matches nothing
+        assert_eq!(evaluate(&criteria(&[("user", "!bob")]), &alice, &[]), Matches::No);
+        assert_eq!(evaluate(&criteria(&[("group", "*,!staff")]), &alice, &strings(&["staff"])), Matches::No);
+    }
+
+    #[test]
+    fn connection_criteria_may_match() {
+        let alice = user("alice");
+        assert_eq!(evaluate(&criteria(&[("address", "10.0.0.0/8")]), &alice, &[]), Matches::Maybe);
+        assert_eq!(evaluate(&criteria(&[("user", "alice"), ("host", "*.example.com")]), &alice, &[]), Matches::Maybe);
+        assert_eq!(evaluate(&criteria(&[("user", "bob"), ("host", "*.example.com")]), &alice, &[]), Matches::No);
+    }
+
+    #[test]
+    fn parses_match_blocks() {
+        let config = parse("match", &[("sshd_config", "AuthorizedKeysFile global\nMatch User alice Group staff\n  AuthorizedKeysFile alice\nMatch all\n  PasswordAuthentication no\n")]);
+        assert_eq!(config.blocks.len(), 3);
+        assert_eq!(config.blocks[0].first("authorizedkeysfile"), Some(&strings(&["global"])));
+        assert_eq!(config.blocks[1].criteria, Some(criteria(&[("user", "alice"), ("group", "staff")])));
+        assert_eq!(config.blocks[1].first("authorizedkeysfile"), Some(&strings(&["alice"])));
+        assert_eq!(config.blocks[2].first("passwordauthentication"), Some(&strings(&["no"])));
+    }
+
+    #[test]
+    fn include_ends_match_block() {
+        let config = parse("include", &[
+            ("sshd_config", "AuthorizedKeysFile global\nInclude $DIR/*.conf\nPasswordAuthentication no\n"),
+            ("alice.conf", "Match User alice\n  AuthorizedKeysFile alice\n"),
+        ]);
+        assert_eq!(config.blocks.len(), 3);
+        assert_eq!(config.blocks[1].criteria, Some(criteria(&[("user", "alice")])));
+        // back in the global section after the included file
+        assert_eq!(config.blocks[2].criteria, None);
+        assert_eq!(config.blocks[2].first("passwordauthentication"), Some(&strings(&["no"])));
+        assert_eq!(config.blocks[1].first("passwordauthentication"), None);
+    }
+
+    #[test]
+    fn include_in_match_block_continues_it() {
+        let config = parse("include-match", &[
+            ("sshd_config", "Match User alice\nInclude $DIR/*.conf\n  AuthorizedKeysFile alice\n"),
+            ("extra.conf", "PasswordAuthentication no\n"),
+        ]);
+        assert_eq!(config.blocks.len(), 2);
+        assert_eq!(config.blocks[1].first("passwordauthentication"), Some(&strings(&["no"])));
+        assert_eq!(config.blocks[1].first("authorizedkeysfile"), Some(&strings(&["alice"])));
+    }
+
+    #[test]
+    fn first_matching_block_wins() {
+        let config = parse("values", &[("sshd_config", concat!(
+            "AuthorizedKeysFile global\n",
+            "AuthorizedKeysFile ignored\n",
+            "Match User alice\n  AuthorizedKeysFile alice\n",
+            "Match Group staff\n  AuthorizedKeysFile staff\n",
+            "Match User carol Address 10.0.0.0/8\n  AuthorizedKeysFile carol-vpn\n",
+        ))]);
+        config.groups.borrow_mut().insert(String::from("alice"), strings(&["staff"]));
+        config.groups.borrow_mut().insert(String::from("bob"), strings(&["staff"]));
+        config.groups.borrow_mut().insert(String::from("carol"), vec![]);
+        config.groups.borrow_mut().insert(String::from("dave"), vec![]);
+        assert_eq!(config.values(&user("alice"), "authorizedkeysfile"), vec![strings(&["alice"])]);
+        assert_eq!(config.values(&user("bob"), "authorizedkeysfile"), vec![strings(&["staff"])]);
+        // the block may or may not apply
+        assert_eq!(config.values(&user("carol"), "authorizedkeysfile"), vec![strings(&["carol-vpn"]), strings(&["global"])]);
+        assert_eq!(config.values(&user("dave"), "authorizedkeysfile"), vec![strings(&["global"])]);
+    }
+
+    #[test]
+    fn falls_back_to_defaults() {
+        let config = parse("defaults", &[("sshd_config", "Match User alice\n  AuthorizedKeysFile alice\n")]);
+        config.groups.borrow_mut().insert(String::from("bob"), vec![]);
+        assert_eq!(config.authorized_keys_files(&user("bob")), strings(&[".ssh/authorized_keys", ".ssh/authorized_keys2"]));
+        assert_eq!(config.paths(&user("bob"), "authorizedprincipalsfile"), Vec::<String>::new());
+    }
+}
@@ -1,7 +1,12 @@
-use crate::diskstate;
-use zbus_systemd::{zbus, zvariant::OwnedObjectPath};
+use crate::diskstate::{self, Module};
+use crate::hog;
+use crate::users;
+use crate::util;
+use crate::modules::HogModule;
+use crate::{HogCommand, ThrottleArgs};
+use zbus_systemd::{zbus, zvariant::OwnedObjectPath, zvariant::OwnedValue, zvariant::Value};
 
-const DISABLE_TIMERS: bool = true;
+/// units stopped by the units module
 const DISABLE_UNITS: &[&str] = &["xrdp.service"];
 
 type ExResult<T> = Result<T, Box<dyn std::error::Error + 'static>>;
@@ -62,27 +67,475 @@ async fn list_units<'a>(
     return units.collect();
 }
 
-pub fn disable_resource(state: &mut diskstate::DiskState) {
-    println!("systemd_units: disable systemd services");
+/// glob patterns of the units that a module stops
+fn unit_patterns(module: &Module) -> Vec<String> {
+    match module {
+        Module::Timers => vec![String::from("*.timer")],
+        Module::Units => DISABLE_UNITS.iter().map(|u| u.to_string()).collect(),
+        _ => vec![],
+    }
+}
+
+/// Whether a stopped unit belongs to a module. The units module also owns units that were stopped
+/// by older versions, so that nothing stays stopped without an owner.
+fn owns_unit(module: &Module, unit: &str) -> bool {
+    let is_timer = unit_patterns(&Module::Timers).iter().any(|p| util::wildcard_match(p, unit));
+    match module {
+        Module::Timers => is_timer,
+        Module::Units => !is_timer,
+        _ => false,
+    }
+}
+
+fn stopped_units(state: &diskstate::DiskState, module: &Module) -> Vec<String> {
+    state.disabled_systemd_units.iter().filter(|unit| owns_unit(module, unit)).cloned().collect()
+}
+
+pub fn disable_resource(state: &mut diskstate::DiskState, module: &Module) {
+    println!("systemd_units: disable systemd services ({})", module);
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let ret = rt.block_on(disable_units(state, unit_patterns(module)));
+    if let Err(e) = ret {
+        panic!("systemd_units: {}", e);
+    }
+}
+
+pub fn enable_resource(state: &mut diskstate::DiskState, module: &Module) {
+    let units = stopped_units(state, module);
+    if units.is_empty() {
+        return;
+    }
+    println!("systemd_units: enable systemd services ({})", module);
+    enable_units(state, units);
+}
+
+/// Stops systemd units: all timers or selected services, depending on the module
+pub struct SystemdUnits(pub Module);
+
+impl HogModule for SystemdUnits {
+    fn apply(&self, state: &mut diskstate::DiskState, _cmd: &HogCommand) {
+        disable_resource(state, &self.0);
+    }
+
+    fn revert(&self, state: &mut diskstate::DiskState) {
+        enable_resource(state, &self.0);
+    }
+
+    fn allow(&self, _state: &mut diskstate::DiskState, _users: &[String]) {}
+
+    fn deny(&self, _state: &mut diskstate::DiskState, _users: &[String]) {}
+
+    fn status(&self, state: &diskstate::DiskState) -> Vec<String> {
+        let units = stopped_units(state, &self.0);
+        vec![format!("{} systemd units are stopped: {}", units.len(), units.join(", "))]
+    }
+
+    fn leftovers(&self, state: &diskstate::DiskState) -> Vec<String> {
+        stopped_units(state, &self.0).iter().map(|unit| format!("stopped: {}", unit)).collect()
+    }
+
+    fn dry_run(&self, state: &diskstate::DiskState, _cmd: &HogCommand) -> Vec<String> {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let units = rt.block_on(active_units(unit_patterns(&self.0)));
+        units
+            .into_iter()
+            .filter(|unit| !state.disabled_systemd_units.contains(unit))
+            .map(|unit| format!("stop {}", unit))
+            .collect()
+    }
+}
+
+/// Freezes the user slices of other users
+pub struct Freeze;
+
+impl HogModule for Freeze {
+    fn apply(&self, state: &mut diskstate::DiskState, _cmd: &HogCommand) {
+        freeze_users(state);
+    }
+
+    fn revert(&self, state: &mut diskstate::DiskState) {
+        thaw_users(state);
+    }
+
+    fn allow(&self, state: &mut diskstate::DiskState, users: &[String]) {
+        let slices = slices_of(state.frozen_slices.clone(), users);
+        thaw_slices(state, slices);
+    }
+
+    fn deny(&self, state: &mut diskstate::DiskState, _users: &[String]) {
+        freeze_users(state);
+    }
+
+    fn status(&self, state: &diskstate::DiskState) -> Vec<String> {
+        vec![format!("{} users are frozen", state.frozen_slices.len())]
+    }
+
+    fn leftovers(&self, state: &diskstate::DiskState) -> Vec<String> {
+        state.frozen_slices.iter().map(|slice| format!("frozen: {}", slice)).collect()
+    }
+
+    fn dry_run(&self, state: &diskstate::DiskState, _cmd: &HogCommand) -> Vec<String> {
+        other_users(state)
+            .into_iter()
+            .filter(|(slice, _user)| !state.frozen_slices.contains(slice))
+            .map(|(slice, user)| format!("freeze {} ({})", slice, user))
+            .collect()
+    }
+}
+
+/// Lowers the resources of the user slices of other users
+pub struct Throttle;
+
+impl HogModule for Throttle {
+    fn apply(&self, state: &mut diskstate::DiskState, cmd: &HogCommand) {
+        throttle_users(state, &cmd.throttle);
+    }
+
+    fn revert(&self, state: &mut diskstate::DiskState) {
+        unthrottle_users(state);
+        if state.throttled_slices.is_empty() {
+            state.throttle_target = None;
+        }
+    }
+
+    fn allow(&self, state: &mut diskstate::DiskState, users: &[String]) {
+        let slices = slices_of(state.throttled_slices.iter().map(|t| t.slice.clone()).collect(), users);
+        unthrottle_slices(state, slices);
+    }
+
+    fn deny(&self, state: &mut diskstate::DiskState, _users: &[String]) {
+        rethrottle_users(state);
+    }
+
+    fn status(&self, state: &diskstate::DiskState) -> Vec<String> {
+        vec![format!("{} users are throttled", state.throttled_slices.len())]
+    }
+
+    fn leftovers(&self, state: &diskstate::DiskState) -> Vec<String> {
+        state.throttled_slices.iter().map(|t| format!("throttled: {}", t.slice)).collect()
+    }
+
+    fn dry_run(&self, state: &diskstate::DiskState, cmd: &HogCommand) -> Vec<String> {
+        let args = &cmd.throttle;
+        let mut properties = vec![format!("CPUWeight={}", args.cpu_weight), format!("IOWeight={}", args.io_weight)];
+        if let Some(quota) = args.cpu_quota {
+            properties.push(format!("CPUQuota={}%", quota));
+        }
+        if let Some(cpus) = &args.allowed_cpus {
+            properties.push(format!("AllowedCPUs={}", cpus));
+        }
+        other_users(state)
+            .into_iter()
+            .filter(|(slice, _user)| !state.throttled_slices.iter().any(|t| t.slice == *slice))
+            .map(|(slice, user)| format!("throttle {} ({}): {}", slice, user, properties.join(" ")))
+            .collect()
+    }
+}
+
+/// start the given units again and forget that we disabled them
+pub fn enable_units(state: &mut diskstate::DiskState, units: Vec<String>) {
     let rt = tokio::runtime::Runtime::new().unwrap();
-    let ret = rt.block_on(disable_units(state));
+    let ret = rt.block_on(start_units(state, units));
     if let Err(e) = ret {
-        eprintln!("Error: {}", e);
-        std::process::exit(1);
+        panic!("systemd_units: {}", e);
     }
 }
 
-pub fn enable_resource(state: &mut diskstate::DiskState) {
-    println!("systemd_units: enable systemd services");
+/// Freeze the user slices of everyone who is not allowed by the hog
+pub fn freeze_users(state: &mut diskstate::DiskState) {
+    println!("systemd_units: freeze other users");
     let rt = tokio::runtime::Runtime::new().unwrap();
-    let ret = rt.block_on(enable_units(state));
+    let ret = rt.block_on(freeze_slices(state));
     if let Err(e) = ret {
-        eprintln!("Error: {}", e);
-        std::process::exit(1);
+        panic!("systemd_units: {}", e);
     }
 }
 
-async fn disable_units(state: &mut diskstate::DiskState) -> ExResult<()> {
+/// Thaw all user slices that we froze
+pub fn thaw_users(state: &mut diskstate::DiskState) {
+    if state.frozen_slices.is_empty() {
+        return;
+    }
+    println!("systemd_units: thaw other users");
+    let slices = state.frozen_slices.clone();
+    thaw_slices(state, slices);
+}
+
+/// thaw the given slices and forget that we froze them
+pub fn thaw_slices(state: &mut diskstate::DiskState, slices: Vec<String>) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let ret = rt.block_on(thaw(state, slices));
+    if let Err(e) = ret {
+        panic!("systemd_units: {}", e);
+    }
+}
+
+/// the slices among `slices` that belong to one of `users`
+fn slices_of(slices: Vec<String>, users: &[String]) -> Vec<String> {
+    slices
+        .into_iter()
+        .filter(|slice| slice_uid(slice).is_some_and(|uid| users.contains(&util::get_username(uid))))
+        .collect()
+}
+
+/// uid of a user slice like user-1000.slice
+pub fn slice_uid(name: &str) -> Option<u32> {
+    name.strip_prefix("user-")?.strip_suffix(".slice")?.parse().ok()
+}
+
+/// The user slice that we run in (e.g. user-1000.slice when run via sudo from a session). None
+/// when we run as a system service.
+fn own_slice() -> Option<String> {
+    let cgroups = std::fs::read_to_string("/proc/self/cgroup").ok()?;
+    // `0::/user.slice/user-1000.slice/session-3.scope`, one line per hierarchy with cgroup v1
+    cgroups
+        .lines()
+        .filter_map(|line| line.splitn(3, ':').nth(2))
+        .flat_map(|path| path.split('/'))
+        .find(|unit| slice_uid(unit).is_some())
+        .map(String::from)
+}
+
+async fn freeze_slices(state: &mut diskstate::DiskState) -> ExResult<()> {
+    let conn = zbus::Connection::system().await.expect("Can't connect");
+    let manager = zbus_systemd::systemd1::ManagerProxy::new(&conn)
+        .await
+        .expect("Can't get systemd manager");
+
+    let message = match &state.hogger {
+        Some(claim) => format!("{}\nYour processes are frozen until the hog is released.", hog::ssh_hogged_message(claim)),
+        None => String::from("Your processes are frozen until the hog is released."),
+    };
+    for (slice, user) in other_user_slices(&manager, state).await {
+        if state.frozen_slices.contains(&slice) {
+            continue;
+        }
+        users::notify_user(&user, &message);
+        match manager.freeze_unit(slice.clone()).await {
+            Err(e) => println!("WARN: failed to freeze {} ({}): {}", slice, user, e),
+            Ok(_) => {
+                println!("froze {} ({})", slice, user);
+                state.frozen_slices.push(slice.clone());
+                diskstate::journal(state, diskstate::Step::Freeze(slice));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// active user slices of users that are not allowed by the hog: (slice, user name)
+fn other_users(state: &diskstate::DiskState) -> Vec<(String, String)> {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let conn = zbus::Connection::system().await.expect("Can't connect");
+        let manager = zbus_systemd::systemd1::ManagerProxy::new(&conn)
+            .await
+            .expect("Can't get systemd manager");
+        other_user_slices(&manager, state).await
+    })
+}
+
+async fn other_user_slices<'a>(
+    manager: &zbus_systemd::systemd1::ManagerProxy<'a>,
+    state: &diskstate::DiskState,
+) -> Vec<(String, String)> {
+    let slices = list_units(manager, vec!["active".to_string()], vec!["user-*.slice".to_string()]).await;
+    // freezing the slice we run in would freeze us in the middle of the hog
+    let own = own_slice();
+    slices
+        .into_iter()
+        .filter(|slice| own.as_ref() != Some(&slice.name))
+        .filter_map(|slice| {
+            let user = hog::get_user(&util::get_username(slice_uid(&slice.name)?))?;
+            match !state.allowed_users.contains(&user.name) && state.settings.locks_out(&user) {
+                true => Some((slice.name, user.name)),
+                false => None,
+            }
+        })
+        .collect()
+}
+
+/// Parse a list of CPUs like `0-3,8` into the bitmask that systemd uses for AllowedCPUs
+fn parse_cpu_list(list: &str) -> Result<Vec<u8>, String> {
+    let mut mask: Vec<u8> = vec![];
+    for range in list.split(',').filter(|r| !r.is_empty()) {
+        let (first, last) = range.split_once('-').unwrap_or((range, range));
+        let parse = |cpu: &str| cpu.trim().parse::<usize>().map_err(|e| format!("invalid cpu list {}: {}", list, e));
+        for cpu in parse(first)?..=parse(last)? {
+            if mask.len() <= cpu / 8 {
+                mask.resize(cpu / 8 + 1, 0);
+            }
+            mask[cpu / 8] |= 1 << (cpu % 8);
+        }
+    }
+    Ok(mask)
+}
+
+/// Lower the resources of everyone who is not allowed by the hog
+pub fn throttle_users(state: &mut diskstate::DiskState, args: &ThrottleArgs) {
+    let allowed_cpus = args.allowed_cpus.as_ref().map(|list| parse_cpu_list(list).unwrap_or_else(|e| panic!("systemd_units: {}", e)));
+    // remembered for users that are denied later on
+    state.throttle_target = Some(diskstate::ThrottledSlice {
+        slice: String::new(),
+        cpu_weight: args.cpu_weight,
+        io_weight: args.io_weight,
+        // CPUQuota is in percent of one CPU per second
+        cpu_quota_per_sec_usec: args.cpu_quota.map(|percent| percent * 10_000),
+        allowed_cpus,
+    });
+    rethrottle_users(state);
+}
+
+/// Throttle everyone who is not allowed by the hog and not throttled yet, like the hog did
+pub fn rethrottle_users(state: &mut diskstate::DiskState) {
+    let target = match state.throttle_target.clone() {
+        Some(target) => target,
+        None => return,
+    };
+    println!("systemd_units: throttle other users");
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let ret = rt.block_on(throttle_slices(state, &target));
+    if let Err(e) = ret {
+        panic!("systemd_units: {}", e);
+    }
+}
+
+/// Restore the resources of all user slices that we throttled
+pub fn unthrottle_users(state: &mut diskstate::DiskState) {
+    if state.throttled_slices.is_empty() {
+        return;
+    }
+    println!("systemd_units: restore resources of other users");
+    let slices = state.throttled_slices.iter().map(|t| t.slice.clone()).collect();
+    unthrottle_slices(state, slices);
+}
+
+/// restore the original properties of the given slices and forget that we throttled them
+pub fn unthrottle_slices(state: &mut diskstate::DiskState, slices: Vec<String>) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let ret = rt.block_on(unthrottle(state, slices));
+    if let Err(e) = ret {
+        panic!("systemd_units: {}", e);
+    }
+}
+
+/// the properties to set, without the ones that the throttle leaves alone
+fn throttle_properties(throttled: &diskstate::ThrottledSlice) -> ExResult<Vec<(String, OwnedValue)>> {
+    let mut properties = vec![
+        (String::from("CPUWeight"), OwnedValue::from(throttled.cpu_weight)),
+        (String::from("IOWeight"), OwnedValue::from(throttled.io_weight)),
+    ];
+    if let Some(quota) = throttled.cpu_quota_per_sec_usec {
+        properties.push((String::from("CPUQuotaPerSecUSec"), OwnedValue::from(quota)));
+    }
+    if let Some(cpus) = &throttled.allowed_cpus {
+        properties.push((String::from("AllowedCPUs"), OwnedValue::try_from(Value::from(cpus.clone()))?));
+    }
+    Ok(properties)
+}
+
+async fn throttle_slices(state: &mut diskstate::DiskState, target: &diskstate::ThrottledSlice) -> ExResult<()> {
+    let conn = zbus::Connection::system().await.expect("Can't connect");
+    let manager = zbus_systemd::systemd1::ManagerProxy::new(&conn)
+        .await
+        .expect("Can't get systemd manager");
+
+    for (slice, user) in other_user_slices(&manager, state).await {
+        if state.throttled_slices.iter().any(|t| t.slice == slice) {
+            continue;
+        }
+        let unit_path = manager.get_unit(slice.clone()).await?;
+        let proxy = zbus_systemd::systemd1::SliceProxy::new(&conn, unit_path).await?;
+        let original = diskstate::ThrottledSlice {
+            slice: slice.clone(),
+            cpu_weight: proxy.cpu_weight().await?,
+            io_weight: proxy.io_weight().await?,
+            cpu_quota_per_sec_usec: match target.cpu_quota_per_sec_usec {
+                Some(_) => Some(proxy.cpu_quota_per_sec_u_sec().await?),
+                None => None,
+            },
+            allowed_cpus: match target.allowed_cpus {
+                Some(_) => Some(proxy.allowed_cp_us().await?),
+                None => None,
+            },
+        };
+        // record the original properties before changing them
+        state.throttled_slices.push(original);
+        diskstate::journal(state, diskstate::Step::Throttle(slice.clone()));
+        match manager.set_unit_properties(slice.clone(), true, throttle_properties(target)?).await {
+            Err(e) => println!("WARN: failed to throttle {} ({}): {}", slice, user, e),
+            Ok(_) => println!("throttled {} ({})", slice, user),
+        }
+    }
+    Ok(())
+}
+
+async fn unthrottle(state: &mut diskstate::DiskState, slices: Vec<String>) -> ExResult<()> {
+    let conn = zbus::Connection::system().await.expect("Can't connect");
+    let manager = zbus_systemd::systemd1::ManagerProxy::new(&conn)
+        .await
+        .expect("Can't get systemd manager");
+
+    for slice in slices {
+        let original = match state.throttled_slices.iter().find(|t| t.slice == slice) {
+            Some(original) => original.clone(),
+            None => continue,
+        };
+        match manager.set_unit_properties(slice.clone(), true, throttle_properties(&original)?).await {
+            // the user logged out in the meantime
+            Err(zbus::Error::MethodError(name, _option, _message))
+                if name == "org.freedesktop.systemd1.NoSuchUnit" => {}
+            Err(e) => {
+                println!("WARN: failed to restore {}: {}", slice, e);
+                continue;
+            }
+            Ok(_) => println!("restored {}", slice),
+        }
+        state.throttled_slices.retain(|t| t.slice != slice);
+        diskstate::store(state);
+    }
+    Ok(())
+}
+
+async fn thaw(state: &mut diskstate::DiskState, slices: Vec<String>) -> ExResult<()> {
+    let conn = zbus::Connection::system().await.expect("Can't connect");
+    let manager = zbus_systemd::systemd1::ManagerProxy::new(&conn)
+        .await
+        .expect("Can't get systemd manager");
+
+    for slice in slices {
+        match manager.thaw_unit(slice.clone()).await {
+            // the user logged out in the meantime
+            Err(zbus::Error::MethodError(name, _option, _message))
+                if name == "org.freedesktop.systemd1.NoSuchUnit" => {}
+            Err(e) => {
+                println!("WARN: failed to thaw {}: {}", slice, e);
+                continue;
+            }
+            Ok(_) => {
+                println!("thawed {}", slice);
+                if let Some(user) = slice_uid(&slice).map(util::get_username) {
+                    users::notify_user(&user, "Your processes have been thawed.");
+                }
+            }
+        }
+        state.frozen_slices.retain(|s| *s != slice);
+        diskstate::store(state);
+    }
+    Ok(())
+}
+
+/// names of the active units that match the patterns
+async fn active_units(patterns: Vec<String>) -> Vec<String> {
+    let conn = zbus::Connection::system().await.expect("Can't connect");
+    let manager = zbus_systemd::systemd1::ManagerProxy::new(&conn)
+        .await
+        .expect("Can't get systemd manager");
+    let units = list_units(&manager, vec!["active".to_string()], patterns).await;
+    units.into_iter().map(|unit| unit.name).collect()
+}
+
+async fn disable_units(state: &mut diskstate::DiskState, patterns: Vec<String>) -> ExResult<()> {
     let conn = zbus::Connection::system().await.expect("Can't connect");
     let manager = zbus_systemd::systemd1::ManagerProxy::new(&conn)
         .await
@@ -94,12 +547,8 @@ async fn disable_units(state: &mut diskstate::DiskState) -> ExResult<()> {
         // "inactive".to_string()
     ];
     let mut units = vec![];
-    if DISABLE_TIMERS {
-        let names = vec!["*.timer".to_string()];
-        units.append(&mut list_units(&manager, states.clone(), names.clone()).await);
-    }
-    for unit in DISABLE_UNITS {
-        let names = vec![unit.to_string()];
+    for pattern in patterns {
+        let names = vec![pattern];
         units.append(&mut list_units(&manager, states.clone(), names).await);
     }
 
@@ -146,23 +595,19 @@ async fn disable_unit<'a>(
         Ok(_) => {
             if !state.disabled_systemd_units.contains(&unit.name) {
                 state.disabled_systemd_units.push(unit.name.clone());
+                diskstate::journal(state, diskstate::Step::StopUnit(unit.name.clone()));
             }
         }
     };
 }
 
-async fn enable_units(state: &mut diskstate::DiskState) -> ExResult<()> {
+async fn start_units(state: &mut diskstate::DiskState, units: Vec<String>) -> ExResult<()> {
     let conn = zbus::Connection::system().await.expect("Can't connect");
     let manager = zbus_systemd::systemd1::ManagerProxy::new(&conn)
         .await
         .expect("Can't get systemd manager");
 
-    let disabled_timers_copy: Vec<String> = state
-        .disabled_systemd_units
-        .iter()
-        .map(|t| t.clone())
-        .collect();
-    for timer_name in disabled_timers_copy {
+    for timer_name in units {
         println!("enabling {}", timer_name);
         match manager
             .start_unit(timer_name.clone(), "fail".to_string())
@@ -190,6 +635,7 @@ async fn enable_units(state: &mut diskstate::DiskState) -> ExResult<()> {
                 // }).collect();
                 // state.disabled_systemd_timers = foo;
                 state.disabled_systemd_units.retain(|t| *t != timer_name);
+                diskstate::store(state);
             }
         };
     }
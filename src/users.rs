@@ -1,5 +1,6 @@
 use netstat::*;
 use crate::util;
+use std::io::Write;
 
 pub fn do_list_users() {
 
@@ -113,10 +114,109 @@ pub fn my_username() -> Option<String> {
     let me = unsafe {
         let cstr = libc::getlogin();
         if cstr.is_null() {
-            println!("WARN: no login name found");
+            eprintln!("WARN: no login name found");
             return None;
         }
         std::str::from_utf8_unchecked(std::slice::from_raw_parts(cstr as *const u8, libc::strlen(cstr)))
     }.to_string();
     return Some(me);
 }
+
+/// (user, terminal) of all login sessions (according to `who`)
+fn who() -> Vec<(String, String)> {
+    let who = match std::process::Command::new("who").output() {
+        Ok(who) => who,
+        Err(_) => return vec![],
+    };
+    String::from_utf8_lossy(&who.stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next()) {
+                (Some(name), Some(tty)) => Some((name.to_string(), tty.to_string())),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// terminals of all login sessions of a user (according to `who`)
+pub fn ttys(user: &str) -> Vec<String> {
+    who().into_iter().filter(|(name, _tty)| name == user).map(|(_name, tty)| tty).collect()
+}
+
+/// users that are logged in right now (according to `who`)
+pub fn logged_in() -> Vec<String> {
+    let mut users: Vec<String> = who().into_iter().map(|(name, _tty)| name).collect();
+    users.sort();
+    users.dedup();
+    users
+}
+
+/// show a popup in all tmux clients of a user
+fn notify_tmux(user: &str, message: &str) {
+    let uid = match util::get_uid(user) {
+        Some(uid) => uid,
+        None => return,
+    };
+    let sockets = match std::fs::read_dir(format!("/tmp/tmux-{}", uid)) {
+        Ok(sockets) => sockets,
+        Err(_) => return, // no tmux server
+    };
+    let popup = format!("echo '{}'; read", message.replace('\'', "'\\''"));
+    for socket in sockets.flatten() {
+        let socket = socket.path();
+        let clients = std::process::Command::new("tmux")
+            .arg("-S").arg(&socket)
+            .args(["list-clients", "-F", "#{client_tty}"])
+            .output();
+        let clients = match clients {
+            Ok(clients) => clients,
+            Err(_) => return, // tmux is not installed
+        };
+        for client in String::from_utf8_lossy(&clients.stdout).lines() {
+            // spawn: the popup blocks until the user closes it
+            let _ = std::process::Command::new("tmux")
+                .arg("-S").arg(&socket)
+                .args(["display-popup", "-c", client, &popup])
+                .spawn();
+        }
+    }
+}
+
+/// Write a message to all terminals of a user with `write`. Unlike notify_user, this works without
+/// being root (as long as the user accepts messages). Returns whether it reached a terminal.
+pub fn write_to_user(user: &str, message: &str) -> bool {
+    let mut written = false;
+    for tty in ttys(user) {
+        let child = std::process::Command::new("write")
+            .args([user, &tty])
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .spawn();
+        if let Ok(mut child) = child {
+            if let Some(mut stdin) = child.stdin.take() {
+                let _ = writeln!(stdin, "{}", message);
+            }
+            written |= child.wait().is_ok_and(|status| status.success());
+        }
+    }
+    written
+}
+
+/// write a message to all terminals and tmux clients of a user (like wall, but for one user)
+pub fn notify_user(user: &str, message: &str) {
+    notify_tmux(user, message);
+    let message = format!("\r\n{}\r\n", message.replace('\n', "\r\n"));
+    for tty in ttys(user) {
+        let path = format!("/dev/{}", tty);
+        let written = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(message.as_bytes()));
+        if let Err(e) = written {
+            println!("WARN: could not notify {} on {}: {}", user, path, e);
+        }
+    }
+}
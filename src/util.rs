@@ -45,7 +45,6 @@ pub fn format_timeout(duration: chrono::Duration) -> String {
     let is_minutes = duration.num_minutes() < 60;
     let is_hours = duration.num_hours() < 24;
     let is_days = duration.num_days() < 7;
-    let is_weeks = duration.num_weeks() < 4;
 
     // format accurdingly
     if is_seconds {
@@ -60,11 +59,8 @@ pub fn format_timeout(duration: chrono::Duration) -> String {
     if is_days {
         return format!("{}d", duration.num_days());
     }
-    if is_weeks {
-        return format!("{}w", duration.num_weeks());
-    }
-
-    unreachable!();
+    // also for long claims and grace periods, so that valid input never panics
+    format!("{}w", duration.num_weeks())
 }
 
 pub fn get_username(uid: u32) -> String {
@@ -77,3 +73,131 @@ pub fn get_username(uid: u32) -> String {
     return name.to_str().unwrap().to_string();
 }
 
+
+/// Match a string against a pattern with the wildcards `*` and `?` (like sshd's match_pattern)
+pub fn wildcard_match(pattern: &str, s: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+    // index after the last `*` in pattern and the position in s that it currently covers
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut i) = (0, 0);
+    while i < s.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == s[i]) {
+            p += 1;
+            i += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p + 1, i));
+            p += 1;
+        } else if let Some((star_p, star_i)) = star {
+            // let the last `*` cover one more character
+            p = star_p;
+            i = star_i + 1;
+            star = Some((star_p, star_i + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Match a string against a comma separated list of wildcard patterns. Patterns prefixed with `!`
+/// are negated: if any of them matches, the whole list does not match (like sshd's
+/// match_pattern_list).
+pub fn match_pattern_list(s: &str, list: &str) -> bool {
+    let mut matched = false;
+    for pattern in list.split(',') {
+        match pattern.strip_prefix('!') {
+            Some(negated) if wildcard_match(negated, s) => return false,
+            Some(_) => {},
+            None => matched |= wildcard_match(pattern, s),
+        }
+    }
+    matched
+}
+
+/// names of all groups the user is a member of (including the primary group)
+pub fn get_groups(username: &str, gid: u32) -> Vec<String> {
+    let name = match std::ffi::CString::new(username) {
+        Ok(name) => name,
+        Err(_) => return vec![],
+    };
+    let mut ngroups: libc::c_int = 64;
+    let mut gids: Vec<libc::gid_t> = vec![0; ngroups as usize];
+    loop {
+        // safe because gids has room for ngroups entries
+        let ret = unsafe { libc::getgrouplist(name.as_ptr(), gid, gids.as_mut_ptr(), &mut ngroups) };
+        if ret >= 0 {
+            break;
+        }
+        // ngroups now contains the required size
+        gids.resize(ngroups as usize, 0);
+    }
+    gids.truncate(ngroups as usize);
+    gids.into_iter().filter_map(get_groupname).collect()
+}
+
+pub fn get_uid(username: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(username).ok()?;
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return None;
+    }
+    // safe because we null check before accessing it
+    Some(unsafe { (*passwd).pw_uid })
+}
+
+pub fn get_groupname(gid: u32) -> Option<String> {
+    let group = unsafe { libc::getgrgid(gid) };
+    if group.is_null() {
+        return None;
+    }
+    // safe because we null check before accessing it
+    let name = unsafe { std::ffi::CStr::from_ptr((*group).gr_name) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_timeouts() {
+        assert_eq!(format_timeout(chrono::Duration::seconds(59)), "59s");
+        assert_eq!(format_timeout(chrono::Duration::minutes(5)), "5m");
+        assert_eq!(format_timeout(chrono::Duration::hours(23)), "23h");
+        assert_eq!(format_timeout(chrono::Duration::days(6)), "6d");
+        assert_eq!(format_timeout(chrono::Duration::weeks(3)), "3w");
+        assert_eq!(format_timeout(chrono::Duration::days(30)), "4w");
+        assert_eq!(format_timeout(chrono::Duration::weeks(60)), "60w");
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(wildcard_match("alice", "alice"));
+        assert!(!wildcard_match("alice", "alicia"));
+        assert!(wildcard_match("a*", "alice"));
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("a?ice", "alice"));
+        assert!(!wildcard_match("a?ice", "aice"));
+        assert!(wildcard_match("*.example.com", "host.sub.example.com"));
+        // `*` has to give back characters for the rest of the pattern to match
+        assert!(wildcard_match("*ab*abc", "abababcabc"));
+        assert!(wildcard_match("a*b?d", "abxbcd"));
+        assert!(!wildcard_match("a*b?d", "abxbd"));
+        assert!(wildcard_match("", ""));
+        assert!(!wildcard_match("", "alice"));
+    }
+
+    #[test]
+    fn matches_pattern_lists() {
+        assert!(match_pattern_list("alice", "bob,alice"));
+        assert!(!match_pattern_list("carol", "bob,alice"));
+        assert!(match_pattern_list("alice", "a*,!bob"));
+        // a matching negation wins, no matter where it is in the list
+        assert!(!match_pattern_list("alice", "a*,!alice"));
+        assert!(!match_pattern_list("alice", "!al*,alice"));
+        // negations alone never match
+        assert!(!match_pattern_list("alice", "!bob"));
+        assert!(!match_pattern_list("alice", ""));
+    }
+}
@@ -0,0 +1,160 @@
+use crate::diskstate::{self, Backend, DiskState, Module};
+use crate::hog;
+use crate::util;
+use std::ffi::CString;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::process::Command;
+
+/// systemd unit that runs `hosthog watch` while hogged
+const UNIT: &str = "hosthog-watch";
+/// Rescan at least this often, in case we missed something (e.g. new users in LDAP)
+const RESCAN_INTERVAL_MS: i32 = 60_000;
+/// wait for more events before rescanning, so that a burst of changes causes one rescan
+const DEBOUNCE: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Start the watcher in the background, unless it is running already
+pub fn start() {
+    let active = Command::new("systemctl").args(["is-active", "--quiet", UNIT]).status();
+    if active.is_ok_and(|status| status.success()) {
+        return;
+    }
+    let started = Command::new("systemd-run")
+        .args(["--quiet", "--collect", "--unit", UNIT, "-p", "Restart=on-failure", &util::prog(), "watch"])
+        .status();
+    match started {
+        Ok(status) if status.success() => println!("watching for new key files and users ({})", UNIT),
+        Ok(status) => println!("WARN: new key files and users are not watched: systemd-run exited with {}", status),
+        Err(e) if e.kind() == ErrorKind::NotFound => println!("WARN: new key files and users are not watched (systemd-run is missing)"),
+        Err(e) => println!("WARN: new key files and users are not watched: systemd-run: {}", e),
+    }
+}
+
+pub fn stop() {
+    let _ = Command::new("systemctl").args(["stop", "--quiet", UNIT]).status();
+}
+
+/// whether the watcher has something to do
+fn watching(state: &DiskState) -> bool {
+    state.hogger.is_some() && state.active_modules.contains(&Module::Ssh) && state.settings.backend == Backend::Overmount
+}
+
+/// the closest directory of `path` that exists
+fn existing_dir(path: &str) -> Option<String> {
+    Path::new(path).ancestors().skip(1).find(|dir| dir.is_dir()).map(|dir| dir.to_string_lossy().into_owned())
+}
+
+/// Directories in which new key files or users show up
+fn watched_dirs(state: &DiskState) -> Vec<String> {
+    // passwd and group are usually replaced, not written in place. Writes to overmounted files
+    // go to the overlay.
+    let mut dirs = vec![String::from("/etc"), hog::OVERLAY_PATH.to_string()];
+    // new home directories of users that NSS does not enumerate
+    dirs.extend(state.settings.home_roots.iter().filter(|root| Path::new(root).is_dir()).cloned());
+    for file in hog::key_files(state) {
+        if let Some(dir) = existing_dir(&file) {
+            if !dirs.contains(&dir) {
+                dirs.push(dir);
+            }
+        }
+    }
+    dirs
+}
+
+struct Inotify {
+    fd: libc::c_int,
+}
+
+impl Inotify {
+    fn new() -> std::io::Result<Inotify> {
+        // safe because it only returns a file descriptor
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Inotify { fd })
+    }
+
+    fn add(&self, dir: &str) {
+        let path = match CString::new(dir) {
+            Ok(path) => path,
+            Err(_) => return,
+        };
+        let mask = libc::IN_CREATE | libc::IN_MOVED_TO | libc::IN_CLOSE_WRITE;
+        // safe because path is a valid C string
+        let wd = unsafe { libc::inotify_add_watch(self.fd, path.as_ptr(), mask) };
+        if wd < 0 {
+            println!("WARN: can not watch {}: {}", dir, std::io::Error::last_os_error());
+        }
+    }
+
+    /// Wait until something changed or the timeout passed
+    fn wait(&self, timeout_ms: i32) {
+        let mut pollfd = libc::pollfd { fd: self.fd, events: libc::POLLIN, revents: 0 };
+        // safe because pollfd lives until poll returns
+        let ready = unsafe { libc::poll(&mut pollfd, 1, timeout_ms) };
+        if ready <= 0 {
+            return;
+        }
+        std::thread::sleep(DEBOUNCE);
+        // drain the events. We rescan everything anyway, so their contents do not matter.
+        let mut buffer = [0u8; 4096];
+        loop {
+            let mut pollfd = libc::pollfd { fd: self.fd, events: libc::POLLIN, revents: 0 };
+            // safe because pollfd lives until poll returns
+            if unsafe { libc::poll(&mut pollfd, 1, 0) } <= 0 {
+                break;
+            }
+            // safe because buffer is large enough for the given length
+            let read = unsafe { libc::read(self.fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+            if read <= 0 {
+                break;
+            }
+        }
+    }
+}
+
+impl Drop for Inotify {
+    fn drop(&mut self) {
+        // safe because we own the file descriptor
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// Overmount key files and users that appeared since the hog, and restrict keys that were added
+/// to overmounted files. Runs until the host is released.
+pub fn do_watch() {
+    loop {
+        let state = {
+            let _lock = diskstate::lock();
+            diskstate::load()
+        };
+        if !watching(&state) {
+            println!("watch: host is not hogged (or not via ssh keys), stop watching");
+            return;
+        }
+        let inotify = Inotify::new().unwrap_or_else(|e| panic!("watch: inotify: {}", e));
+        for dir in watched_dirs(&state) {
+            inotify.add(&dir);
+        }
+        inotify.wait(RESCAN_INTERVAL_MS);
+
+        // the state may have changed while we waited. Hold the lock until the rescan is stored,
+        // so that a concurrent hosthog does not store an outdated state.
+        let _lock = diskstate::lock();
+        let mut state = diskstate::load();
+        if !watching(&state) || state.transaction.is_some() {
+            continue;
+        }
+        let changes = hog::ssh_changes(&state);
+        if changes.is_empty() {
+            continue;
+        }
+        println!("{}", changes.join(", "));
+        hog::guarded(&mut state, |state| {
+            diskstate::begin(state, diskstate::Operation::Rescan);
+            hog::rescan_ssh(state);
+            diskstate::commit(state);
+        });
+    }
+}